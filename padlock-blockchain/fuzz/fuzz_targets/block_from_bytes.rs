@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use padlock_blockchain::block::Block;
+
+fuzz_target!(|data: &[u8]| {
+    // Anything that decodes has to encode back to the same bytes
+    if let Ok(block) = Block::from_bytes(data) {
+        let bytes = block.to_bytes().expect("decoded block encodes");
+        assert_eq!(bytes, data);
+    }
+});
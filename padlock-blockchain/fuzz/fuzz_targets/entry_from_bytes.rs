@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use padlock_blockchain::block::Entry;
+
+fuzz_target!(|data: &[u8]| {
+    // Anything that decodes has to encode back to the same bytes
+    if let Ok(entry) = Entry::from_bytes(data) {
+        let bytes = entry.to_bytes().expect("decoded entry encodes");
+        assert_eq!(bytes, data);
+    }
+});
@@ -1,17 +1,80 @@
-use crate::randomx_bindings::{
-    RandomxCache, RandomxError, RandomxFlags, RandomxVm,
-};
+use crate::randomx;
+use crate::randomx_bindings::RandomxError;
 
 use blake2::{Blake2b, Digest};
-use bls_signatures::{PublicKey, Serialize, Signature};
+use bls_signatures::{PrivateKey, PublicKey, Serialize, Signature};
 use merkle_tree::MerkleTree;
 use rocks::prelude::*;
+use tracing::debug;
 
+use crate::encoding::{Decoder, Encoder, EncodingError, EncodingErrorKind};
+use crate::signatures::{SignatureCache, SignedMessages};
+use crate::target::{self, U256};
+use crate::threshold::{
+    PartialSignature, ThresholdKeys, MAX_THRESHOLD_KEYS_SIZE,
+};
 use crate::KeyType;
 
-use std::{convert::TryInto, error::Error, fmt};
+use std::collections::HashSet;
+use std::{convert::TryInto, error::Error, fmt, io};
+
+/// The version of `BlockHeader::commitment`'s format, which is its first byte.
+pub const HEADER_COMMITMENT_VERSION: u8 = 1;
+
+/// The longest payload an entry can encode. Networks set their own, lower,
+/// limits in `NetworkParams`.
+pub const MAX_PAYLOAD_SIZE: usize = 1024;
+
+/// The longest an encoded entry can be: its version, 255 coinfile hashes, the
+/// output hash, the longest payload, both time locks, the most threshold keys
+/// and 255 bytes of proof of work, along with their lengths and tags.
+pub const MAX_ENTRY_SIZE: usize =
+    1 + 1 + 255 * 32 + 32 + 2 + MAX_PAYLOAD_SIZE + 18 + MAX_KEY_SIZE + 1 + 255;
+
+/// Time locks below this are block heights, and the rest are Unix timestamps
+/// compared with the chain's median time past.
+pub const LOCK_TIME_THRESHOLD: u64 = 500_000_000;
+
+/// The version is the first byte of an encoded entry, and decides how the rest
+/// is laid out and what is signed. Entries from before versions have no such
+/// byte and aren't decoded at all, see `STORAGE_VERSION`.
+///
+/// Version 1 signs the chain id along with the entry, and has 8 byte coinfile
+/// and output hashes.
+pub const ENTRY_VERSION_1: u8 = 1;
+
+/// Version 2 has 32 byte coinfile and output hashes.
+pub const ENTRY_VERSION_2: u8 = 2;
+
+/// Version 3 adds a payload after the output hash, prefixed with its length as
+/// a u16.
+pub const ENTRY_VERSION_3: u8 = 3;
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+/// Version 4 adds `valid_after` and `valid_until` after the payload. Each is a
+/// bool saying whether it is set, followed by its u64 if it is.
+pub const ENTRY_VERSION_4: u8 = 4;
+
+/// Version 5 can be owned by threshold keys instead of a single key, and its
+/// signers sign their public key along with the entry.
+pub const ENTRY_VERSION_5: u8 = 5;
+
+/// The version new entries are encoded with.
+pub const ENTRY_VERSION: u8 = ENTRY_VERSION_5;
+
+/// How many bytes of each coinfile and output hash version 1 entries carry.
+/// They are the first bytes of the full hashes, and are padded with zeros in
+/// memory.
+pub const V1_HASH_LEN: usize = 8;
+
+// Which kind of key an encoded entry carries
+pub(crate) const PUBLIC_KEY_TAG: u8 = 0x00;
+pub(crate) const PUBLIC_KEY_INDEX_TAG: u8 = 0x01;
+const THRESHOLD_KEYS_TAG: u8 = 0x02;
+
+// The longest key an entry can have, which is the most threshold keys
+const MAX_KEY_SIZE: usize = 1 + MAX_THRESHOLD_KEYS_SIZE;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Block {
     pub entries: Vec<Entry>,
     pub header: BlockHeader,
@@ -21,15 +84,9 @@ pub struct Block {
 
 impl Block {
     pub fn new(
-        previous_hash: [u8; 32],
-        height: usize,
+        fields: HeaderFields,
         mempool_entries: Vec<MempoolEntry>,
         randomx_input: Vec<u8>,
-        timestamp: u64,
-        difficulty_target: f32,
-        entry_difficulty_multiplier: f32,
-        max_allowed_entry_difficulty: f32,
-        miner_address: [u8; 32],
     ) -> Result<Self, BlockError> {
         let mut signatures: Vec<Signature> = Vec::new();
         let mut entries: Vec<Entry> = Vec::new();
@@ -43,15 +100,9 @@ impl Block {
         let signature = bls_signatures::aggregate(&signatures)?.as_bytes();
 
         let block = Block::new_with_signature(
-            previous_hash,
-            height,
+            fields,
             entries,
             randomx_input,
-            timestamp,
-            difficulty_target,
-            entry_difficulty_multiplier,
-            max_allowed_entry_difficulty,
-            miner_address,
             signature,
         )?;
 
@@ -59,43 +110,22 @@ impl Block {
     }
 
     pub fn new_with_signature(
-        previous_hash: [u8; 32],
-        height: usize,
+        fields: HeaderFields,
         entries: Vec<Entry>,
         randomx_input: Vec<u8>,
-        timestamp: u64,
-        difficulty_target: f32,
-        entry_difficulty_multiplier: f32,
-        max_allowed_entry_difficulty: f32,
-        miner_address: [u8; 32],
         signature: Vec<u8>,
     ) -> Result<Self, BlockError> {
         let merkle_tree = MerkleTree::new(&entries);
         let merkle_root = merkle_tree.root;
 
-        let header = BlockHeader::new(
-            previous_hash,
-            height,
-            merkle_root,
-            timestamp,
-            difficulty_target,
-            0f32, // entry difficulty
-            entry_difficulty_multiplier,
-            max_allowed_entry_difficulty,
-            miner_address,
-            signature,
-        );
-
-        let flags = RandomxFlags::default();
-        let cache = RandomxCache::new(flags, &header.concat())?;
-        let vm = RandomxVm::new(flags, &cache)?;
-        let hash = vm.hash(&randomx_input);
+        let header = BlockHeader::new(fields, merkle_root, 0f32, signature);
 
+        // The hash is only known once the block is mined
         let mut block = Block {
             entries,
             header,
             randomx_input,
-            hash,
+            hash: [0u8; 32],
         };
 
         block.header.entry_difficulty = block.entry_difficulty()?;
@@ -103,19 +133,9 @@ impl Block {
         Ok(block)
     }
 
-    pub fn miner_difficulty(&self) -> usize {
-        let leading_zeros = {
-            let mut leading_zeros = 0;
-            for i in self.hash.iter() {
-                leading_zeros += i.to_le().leading_zeros();
-                if i.leading_zeros() < 8 {
-                    break;
-                }
-            }
-            leading_zeros
-        };
-
-        2usize.pow(leading_zeros)
+    /// The work the block's hash shows on its own, without its entries.
+    pub fn miner_difficulty(&self) -> f64 {
+        target::hash_work(&self.hash)
     }
 
     pub fn entry_difficulty(&self) -> Result<f32, BlockError> {
@@ -131,255 +151,466 @@ impl Block {
         Ok(entry_difficulty)
     }
 
-    pub fn difficulty(&self) -> Result<f32, BlockError> {
+    pub fn difficulty(&self) -> Result<f64, BlockError> {
         let miner_difficulty = self.miner_difficulty();
         let entry_difficulty = self.entry_difficulty()?;
 
-        Ok(miner_difficulty as f32 + (entry_difficulty * self.header.entry_difficulty_multiplier))
+        let entry_bonus =
+            entry_difficulty * self.header.entry_difficulty_multiplier;
+
+        Ok(miner_difficulty + entry_bonus as f64)
     }
 
-    pub fn calc_hash(&self) -> Result<[u8; 32], BlockError> {
-        let key = self.header.concat();
+    pub fn calc_hash(
+        &self,
+        seed_key: &[u8; 32],
+    ) -> Result<[u8; 32], BlockError> {
+        self.header.calc_hash(seed_key, &self.randomx_input)
+    }
 
-        let flags = RandomxFlags::default();
-        let cache = RandomxCache::new(flags, &key)?;
-        let vm = RandomxVm::new(flags, &cache)?;
-        let hash = vm.hash(&self.randomx_input);
+    /// Checks the aggregated signature of the block against every entry's
+    /// public key and message, skipping the pairing if `cache` has all of
+    /// their signatures. Needs access to the databse in order to retrieve
+    /// public keys from indexes.
+    pub fn check_signature(
+        &self,
+        db: &rocks::db::DB,
+        chain_id: u32,
+        cache: &SignatureCache,
+    ) -> Result<(), BlockError> {
+        if !self.signed_messages(db, chain_id)?.verify(cache) {
+            return Err(BlockError::new(BlockErrorKind::InvalidSignature));
+        }
 
-        Ok(hash)
+        Ok(())
     }
 
-    /// Collects every public key and message, then checks it against the aggregated signature of
-    /// the block. Needs access to the databse in order to retrieve public keys from indexes.
-    pub fn check_signature(&self, db: &rocks::db::DB) -> Result<(), BlockError> {
+    /// Collects every public key and message, along with the aggregated
+    /// signature of the block. Entries owned by threshold keys have one of
+    /// each for every signer.
+    ///
+    /// Every message includes its signer's key, either in the entry or before
+    /// it, and no message may appear twice, so no two signatures in the
+    /// aggregate are over the same message. That rules out rogue key attacks,
+    /// where a key is made up from other keys so that a signature by one
+    /// party verifies for all of them.
+    pub fn signed_messages(
+        &self,
+        db: &rocks::db::DB,
+        chain_id: u32,
+    ) -> Result<SignedMessages, BlockError> {
         // Get every public key from each entry
         let mut public_keys: Vec<PublicKey> = Vec::new();
         let mut messages: Vec<Vec<u8>> = Vec::new();
+        let mut seen_messages = HashSet::new();
+
+        for (entry_index, entry) in self.entries.iter().enumerate() {
+            let signer_keys = entry
+                .resolve_signer_keys(db)
+                .map_err(|error| error.at_entry(entry_index))?;
+
+            for public_key in signer_keys {
+                let message = entry
+                    .signing_message(chain_id, &public_key)
+                    .map_err(|error| error.at_entry(entry_index))?;
+                if !seen_messages.insert(message.clone()) {
+                    return Err(BlockError::new(
+                        BlockErrorKind::DuplicateMessage,
+                    )
+                    .at_entry(entry_index));
+                }
 
-        for entry in &self.entries {
-            if let Some(public_key_bytes) = &entry.public_key {
-
-                let public_key = PublicKey::from_bytes(&public_key_bytes)?;
                 public_keys.push(public_key);
-
-            } else if let Some(public_key_index) = &entry.public_key_index {
-
-                let public_key_index = public_key_index.to_le_bytes();
-
-                let key =
-                    KeyType::make_key(KeyType::PublicKey, &public_key_index);
-
-                match db.get(ReadOptions::default_instance(), &key) {
-					Ok(public_key_bytes) => {
-		                let public_key = PublicKey::from_bytes(&public_key_bytes)?;
-		              	public_keys.push(public_key);
-					}
-					
-                	Err(_) => {
-                		return Err(BlockError::new(BlockErrorKind::NoPublicKeyFound));
-                	}
-                }
-            } else {
-                return Err(BlockError::new(
-                    BlockErrorKind::NoPublicKeyFound
-                ));
+                messages.push(message);
             }
-
-            let message = entry.to_bytes()?;
-            messages.push(message);
         }
 
-        // Convert messages to Vec<&[u8]>
-        let messages: Vec<&[u8]> =
-            messages.iter().map(|message| &message[..]).collect();
-
-        let signature = Signature::from_bytes(&self.header.signature)?;
-        if !bls_signatures::verify_messages(&signature, &messages, &public_keys)
-        {
-            return Err(BlockError::new(BlockErrorKind::InvalidSignature));
-        }
+        Ok(SignedMessages {
+            signature: Signature::from_bytes(&self.header.signature)?,
+            public_keys,
+            messages,
+        })
+    }
 
-        Ok(())
+    pub fn calc_merkle_root(&self) -> [u8; 28] {
+        MerkleTree::new(&self.entries).root
     }
 
     pub fn is_merkle_root_valid(&self) -> bool {
-        let merkle_tree = MerkleTree::new(&self.entries);
+        self.calc_merkle_root() == self.header.merkle_root
+    }
 
-        merkle_tree.root == self.header.merkle_root
+    /// Recomputes the RandomX hash and checks it against the stored one.
+    pub fn is_hash_valid(
+        &self,
+        seed_key: &[u8; 32],
+    ) -> Result<bool, BlockError> {
+        Ok(self.calc_hash(seed_key)? == self.hash)
     }
 
+    /// Encodes the block canonically: the header, the entries, each length
+    /// prefixed, then the RandomX input and the hash.
     pub fn to_bytes(&self) -> Result<Vec<u8>, BlockError> {
-        let mut entries_bytes: Vec<Vec<u8>> = Vec::new();
-
-        for entry in &self.entries {
-            entries_bytes.push(entry.to_bytes()?);
+        let mut encoder = Encoder::new();
+        self.header.encode(&mut encoder)?;
+
+        encoder.put_len(self.entries.len())?;
+        for (entry_index, entry) in self.entries.iter().enumerate() {
+            let entry_bytes = entry
+                .to_bytes()
+                .map_err(|error| error.at_entry(entry_index))?;
+            encoder.put_bytes(&entry_bytes)?;
         }
 
-        let block_with_serialized_entries = BlockWithSerializedEntries {
-            entries_bytes,
-            header: self.header.clone(),
-            randomx_input: self.randomx_input.clone(),
-            hash: self.hash.clone()
-        };
+        encoder.put_bytes(&self.randomx_input)?;
+        encoder.put_array(&self.hash);
 
-        let block_bytes = rmp_serde::to_vec(&block_with_serialized_entries)?;
-        Ok(block_bytes)
+        Ok(encoder.finish())
     }
 
+    /// Decodes a block as it was stored, without recomputing its hash or
+    /// checking it in any other way. Blocks from untrusted sources need to be
+    /// validated, which includes `is_hash_valid`.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, BlockError> {
-        let block_with_serialized_entries: BlockWithSerializedEntries = rmp_serde::from_slice(bytes)?;
+        let mut decoder = Decoder::new(bytes);
+        let header = BlockHeader::decode(&mut decoder)?;
 
+        let entries_len = decoder.get_u32()?;
         let mut entries: Vec<Entry> = Vec::new();
 
-        for entry_bytes in block_with_serialized_entries.entries_bytes {
-            let entry = Entry::from_bytes(&entry_bytes)?;
+        for entry_index in 0..entries_len as usize {
+            let entry_len = decoder.get_u32()? as usize;
+            if entry_len > MAX_ENTRY_SIZE {
+                return Err(BlockError::new(BlockErrorKind::LengthTooBig {
+                    maximum: MAX_ENTRY_SIZE,
+                    found: entry_len,
+                })
+                .at_entry(entry_index));
+            }
+
+            let entry = decoder
+                .get_slice(entry_len)
+                .map_err(BlockError::from)
+                .and_then(Entry::from_bytes)
+                .map_err(|error| error.at_entry(entry_index))?;
             entries.push(entry);
         }
 
-        let block = Block::new_with_signature(
-            block_with_serialized_entries.header.previous_hash,
-            block_with_serialized_entries.header.height,
+        let randomx_input = decoder.get_bytes()?;
+        let hash = decoder.get_array()?;
+        decoder.finish()?;
+
+        let block = Block {
             entries,
-            block_with_serialized_entries.randomx_input,
-            block_with_serialized_entries.header.timestamp,
-            block_with_serialized_entries.header.difficulty_target,
-            block_with_serialized_entries.header.entry_difficulty_multiplier,
-            block_with_serialized_entries.header.max_allowed_entry_difficulty,
-            block_with_serialized_entries.header.miner_address,
-            block_with_serialized_entries.header.signature,
-        )?;
+            header,
+            randomx_input,
+            hash,
+        };
 
         Ok(block)
     }
 }
 
-/// Because entries are serialized differently than everything else, this struct is for the mid
-/// point in serialization; the entries have been serialized, but the rest hasn't.
-#[derive(serde::Serialize, serde::Deserialize)]
-struct BlockWithSerializedEntries {
-    pub entries_bytes: Vec<Vec<u8>>,
-    pub header: BlockHeader,
-    pub randomx_input: Vec<u8>,
-    pub hash: [u8; 32],
-}
-
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct BlockHeader {
+    /// Which rules the block follows, and which deployments its miner signals
+    /// for.
+    pub version: u32,
     pub previous_hash: [u8; 32],
-    pub height: usize,
+    pub height: u64,
     pub merkle_root: [u8; 28],
     pub timestamp: u64,
-    pub difficulty_target: f32,
+    pub target: u32,
     pub entry_difficulty: f32,
     pub entry_difficulty_multiplier: f32,
     pub max_allowed_entry_difficulty: f32,
+    /// The least difficulty each of the block's entries must have.
+    pub min_entry_difficulty: u64,
     pub miner_address: [u8; 32],
     pub signature: Vec<u8>, // serde doesn't suport arrays past length 32, so vec is used
 }
 
+/// The fields of a block header that its miner picks. The rest depend on the
+/// block's entries.
+#[derive(Clone, Debug)]
+pub struct HeaderFields {
+    pub version: u32,
+    pub previous_hash: [u8; 32],
+    pub height: u64,
+    pub timestamp: u64,
+    pub target: u32,
+    pub entry_difficulty_multiplier: f32,
+    pub max_allowed_entry_difficulty: f32,
+    pub min_entry_difficulty: u64,
+    pub miner_address: [u8; 32],
+}
+
 impl BlockHeader {
     pub fn new(
-        previous_hash: [u8; 32],
-        height: usize,
+        fields: HeaderFields,
         merkle_root: [u8; 28],
-        timestamp: u64,
-        difficulty_target: f32,
         entry_difficulty: f32,
-        entry_difficulty_multiplier: f32,
-        max_allowed_entry_difficulty: f32,
-        miner_address: [u8; 32],
         signature: Vec<u8>,
     ) -> Self {
         BlockHeader {
+            version: fields.version,
+            previous_hash: fields.previous_hash,
+            height: fields.height,
+            merkle_root,
+            timestamp: fields.timestamp,
+            target: fields.target,
+            entry_difficulty,
+            entry_difficulty_multiplier: fields.entry_difficulty_multiplier,
+            max_allowed_entry_difficulty: fields.max_allowed_entry_difficulty,
+            min_entry_difficulty: fields.min_entry_difficulty,
+            miner_address: fields.miner_address,
+            signature,
+        }
+    }
+
+    /// Hashes this header along with `randomx_input` with RandomX, keyed by
+    /// the seed key of the header's epoch.
+    pub fn calc_hash(
+        &self,
+        seed_key: &[u8; 32],
+        randomx_input: &[u8],
+    ) -> Result<[u8; 32], BlockError> {
+        let hash = randomx::hash(seed_key, &self.pow_input(randomx_input)?)?;
+
+        Ok(hash)
+    }
+
+    /// What gets hashed with RandomX: the header's commitment, followed by the
+    /// miner's `randomx_input`.
+    pub fn pow_input(
+        &self,
+        randomx_input: &[u8],
+    ) -> Result<Vec<u8>, EncodingError> {
+        Ok([self.commitment()?, randomx_input.to_vec()].concat())
+    }
+
+    /// The target the block's hash has to meet. The entries lower the
+    /// difficulty of `target` by the entry difficulty the header claims,
+    /// scaled by the multiplier, which raises the target.
+    pub fn effective_target(&self) -> U256 {
+        // Casting a float to an integer truncates and saturates the same way
+        // everywhere, and NaN becomes zero
+        let entry_bonus =
+            (self.entry_difficulty * self.entry_difficulty_multiplier) as u64;
+
+        target::lower_difficulty(U256::from_compact(self.target), entry_bonus)
+    }
+
+    /// Whether `hash`, read as a 256 bit integer, is at or below the
+    /// effective target.
+    pub fn meets_target(&self, hash: &[u8; 32]) -> bool {
+        U256::from_be_bytes(*hash) <= self.effective_target()
+    }
+
+    /// The bytes of the header the proof of work commits to: the commitment
+    /// version followed by the header's canonical encoding. Every field is
+    /// included, so none can be changed without redoing the proof of work.
+    pub fn commitment(&self) -> Result<Vec<u8>, EncodingError> {
+        Ok([vec![HEADER_COMMITMENT_VERSION], self.to_bytes()?].concat())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodingError> {
+        let mut encoder = Encoder::new();
+        self.encode(&mut encoder)?;
+
+        Ok(encoder.finish())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let mut decoder = Decoder::new(bytes);
+        let header = BlockHeader::decode(&mut decoder)?;
+        decoder.finish()?;
+
+        Ok(header)
+    }
+
+    fn encode(&self, encoder: &mut Encoder) -> Result<(), EncodingError> {
+        // Destructured so that a new field can't be left out by accident
+        let BlockHeader {
+            version,
             previous_hash,
             height,
             merkle_root,
             timestamp,
-            difficulty_target,
+            target,
             entry_difficulty,
             entry_difficulty_multiplier,
             max_allowed_entry_difficulty,
+            min_entry_difficulty,
             miner_address,
             signature,
-        }
+        } = self;
+
+        encoder.put_u32(*version);
+        encoder.put_array(previous_hash);
+        encoder.put_u64(*height);
+        encoder.put_array(merkle_root);
+        encoder.put_u64(*timestamp);
+        encoder.put_u32(*target);
+        encoder.put_f32(*entry_difficulty);
+        encoder.put_f32(*entry_difficulty_multiplier);
+        encoder.put_f32(*max_allowed_entry_difficulty);
+        encoder.put_u64(*min_entry_difficulty);
+        encoder.put_array(miner_address);
+        encoder.put_bytes(signature)
     }
 
-    pub fn concat(&self) -> Vec<u8> {
-        [
-            self.previous_hash.to_vec(),
-            self.height.to_le_bytes().into(),
-            self.merkle_root.to_vec(),
-            self.timestamp.to_le_bytes().into(),
-            self.difficulty_target.to_le_bytes().into(),
-            self.miner_address.to_vec(),
-            self.signature.clone(),
-        ]
-        .concat()
+    fn decode(decoder: &mut Decoder) -> Result<Self, EncodingError> {
+        Ok(BlockHeader {
+            version: decoder.get_u32()?,
+            previous_hash: decoder.get_array()?,
+            height: decoder.get_u64()?,
+            merkle_root: decoder.get_array()?,
+            timestamp: decoder.get_u64()?,
+            target: decoder.get_u32()?,
+            entry_difficulty: decoder.get_f32()?,
+            entry_difficulty_multiplier: decoder.get_f32()?,
+            max_allowed_entry_difficulty: decoder.get_f32()?,
+            min_entry_difficulty: decoder.get_u64()?,
+            miner_address: decoder.get_array()?,
+            signature: decoder.get_bytes()?,
+        })
     }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
 pub struct Entry {
-    pub coinfile_hashes: Vec<[u8; 8]>,
-    pub output_hash: [u8; 8],
+    pub version: u8,
+    pub coinfile_hashes: Vec<[u8; 32]>,
+    pub output_hash: [u8; 32],
+    /// Application data, such as a document digest, a memo or a content type
+    /// tag. It is signed and hashed along with the rest of the entry. Empty
+    /// if there is none, and always empty before version 3.
+    pub payload: Vec<u8>,
+    /// The entry can only be in blocks after this height, or after this
+    /// median time past if it is at least `LOCK_TIME_THRESHOLD`. Only version
+    /// 4 entries can set it.
+    pub valid_after: Option<u64>,
+    /// The entry can't be in blocks after this height, or after this median
+    /// time past if it is at least `LOCK_TIME_THRESHOLD`, so it can't be
+    /// replayed once it is stale. Only version 4 entries can set it.
+    pub valid_until: Option<u64>,
     pub public_key: Option<Vec<u8>>, // serde can't support arrays past 32, so a vec is used instead
     pub public_key_index: Option<u64>,
+    /// Set instead of `public_key` or `public_key_index` when the entry is
+    /// owned by several keys. Only version 5 entries can have them.
+    pub threshold_keys: Option<ThresholdKeys>,
     pub proof_of_work: Vec<u8>,
 }
 
 impl Entry {
     pub fn new(
-        coinfile_hashes: Vec<[u8; 8]>,
-        output_hash: [u8; 8],
+        coinfile_hashes: Vec<[u8; 32]>,
+        output_hash: [u8; 32],
+        payload: Vec<u8>,
         public_key: Option<Vec<u8>>,
         public_key_index: Option<u64>,
         proof_of_work: Vec<u8>,
     ) -> Self {
         Self {
+            version: ENTRY_VERSION,
             coinfile_hashes,
             output_hash,
+            payload,
+            valid_after: None,
+            valid_until: None,
             public_key,
             public_key_index,
+            threshold_keys: None,
             proof_of_work,
         }
     }
 
     pub fn to_bytes(&self) -> Result<Vec<u8>, BlockError> {
+        let hash_len = self.hash_len()?;
+
         if self.coinfile_hashes.len() > 255 {
-            return Err(BlockError::new(BlockErrorKind::TooManyCoinfileHashes));
+            return Err(BlockError::new(BlockErrorKind::TooManyCoinfileHashes {
+                maximum: 255,
+                found: self.coinfile_hashes.len(),
+            }));
         }
 
         let coinfile_hashes_len = self.coinfile_hashes.len() as u8;
 
+        let has_payload = has_payload(self.version);
+        if !has_payload && !self.payload.is_empty() {
+            return Err(BlockError::new(BlockErrorKind::PayloadNotSupported));
+        }
+        check_payload_len(self.payload.len())?;
+
+        let has_time_locks = has_time_locks(self.version);
+        if !has_time_locks
+            && (self.valid_after.is_some() || self.valid_until.is_some())
+        {
+            return Err(BlockError::new(BlockErrorKind::TimeLocksNotSupported));
+        }
+
         let mut coinfile_hashes_bytes: Vec<u8> = Vec::new();
         for coinfile_hash in &self.coinfile_hashes {
-            coinfile_hashes_bytes.append(&mut coinfile_hash.to_vec());
+            coinfile_hashes_bytes
+                .extend_from_slice(encoded_hash(coinfile_hash, hash_len)?);
         }
 
-        // 0 means it is a public_key, 1 means it is a public_key_index
-        let mut is_public_key_index = 0u8;
+        let mut key_type = PUBLIC_KEY_TAG;
 
-        let mut public_key: Vec<u8> = match &self.public_key {
-            Some(public_key) => public_key.clone(),
-            None => {
-                is_public_key_index = 1;
-                self.public_key_index
-                    .ok_or(BlockError::new(BlockErrorKind::NoPublicKeyFound))?
-                    .to_le_bytes()
-                    .to_vec()
+        let key = (
+            &self.public_key,
+            self.public_key_index,
+            &self.threshold_keys,
+        );
+        let mut public_key: Vec<u8> = match key {
+            (Some(public_key), None, None) => public_key.clone(),
+            (None, Some(public_key_index), None) => {
+                key_type = PUBLIC_KEY_INDEX_TAG;
+                public_key_index.to_le_bytes().to_vec()
+            }
+            (None, None, Some(threshold_keys)) => {
+                if !has_threshold_keys(self.version) {
+                    return Err(BlockError::new(
+                        BlockErrorKind::ThresholdKeysNotSupported,
+                    ));
+                }
+
+                key_type = THRESHOLD_KEYS_TAG;
+                threshold_keys.to_bytes()?
             }
+            (None, None, None) => {
+                return Err(BlockError::new(BlockErrorKind::NoPublicKeyFound))
+            }
+            _ => return Err(BlockError::new(BlockErrorKind::MultipleKeys)),
         };
 
         if self.proof_of_work.len() > u8::MAX as usize {
-            return Err(BlockError::new(BlockErrorKind::PoWTooLong));
+            return Err(BlockError::new(BlockErrorKind::PoWTooLong {
+                maximum: u8::MAX as usize,
+                found: self.proof_of_work.len(),
+            }));
         }
         let proof_of_work_len = self.proof_of_work.len() as u8;
 
-        let mut bytes: Vec<u8> = Vec::new();
+        let mut bytes: Vec<u8> = vec![self.version];
         bytes.append(&mut coinfile_hashes_len.to_le_bytes().to_vec());
         bytes.append(&mut coinfile_hashes_bytes.to_vec());
-        bytes.append(&mut self.output_hash.to_vec());
-        bytes.append(&mut is_public_key_index.to_le_bytes().to_vec());
+        bytes.extend_from_slice(encoded_hash(&self.output_hash, hash_len)?);
+        if has_payload {
+            bytes.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(&self.payload);
+        }
+        if has_time_locks {
+            for time_lock in &[self.valid_after, self.valid_until] {
+                bytes.push(time_lock.is_some() as u8);
+                if let Some(time_lock) = time_lock {
+                    bytes.extend_from_slice(&time_lock.to_le_bytes());
+                }
+            }
+        }
+        bytes.append(&mut key_type.to_le_bytes().to_vec());
         bytes.append(&mut public_key);
         bytes.append(&mut proof_of_work_len.to_le_bytes().to_vec());
         bytes.append(&mut self.proof_of_work.clone());
@@ -387,67 +618,184 @@ impl Entry {
         Ok(bytes)
     }
 
+    /// Decodes an entry, failing on anything that `to_bytes` wouldn't have
+    /// produced rather than panicking, as entries come from the network.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, BlockError> {
-        let mut bytes = bytes.iter().copied();
-        let coinfile_hashes_len = bytes.next().unwrap().clone() as usize;
-        let mut coinfile_hashes = Vec::new();
+        let mut decoder = Decoder::new(bytes);
 
-        for _ in 0..coinfile_hashes_len {
-            let coinfile_hash: Vec<u8> = (&mut bytes).take(8).collect();
-            coinfile_hashes.push(coinfile_hash.try_into().unwrap());
-        }
+        let version = decoder.get_u8()?;
+        let hash_len = hash_len(version)?;
 
-        let output_hash: Vec<u8> = (&mut bytes).take(8).collect();
-        let output_hash: [u8; 8] = output_hash.try_into().unwrap();
+        let coinfile_hashes_len = decoder.get_u8()?;
+        let coinfile_hashes = (0..coinfile_hashes_len)
+            .map(|_| decode_hash(&mut decoder, hash_len))
+            .collect::<Result<Vec<[u8; 32]>, _>>()?;
 
-        let is_public_key_index = bytes.next().unwrap().to_owned();
-        let mut public_key = None;
-        let mut public_key_index = None;
+        let output_hash = decode_hash(&mut decoder, hash_len)?;
 
-        if is_public_key_index == 0x00 {
-            public_key = Some((&mut bytes).take(48).collect());
-        } else if is_public_key_index == 0x01 {
-            let public_key_index_bytes: Vec<u8> =
-                (&mut bytes).take(8).collect();
+        let payload = if has_payload(version) {
+            let payload_len = u16::from_le_bytes(decoder.get_array()?);
+            check_payload_len(payload_len as usize)?;
+            decoder.get_slice(payload_len as usize)?.to_vec()
+        } else {
+            Vec::new()
+        };
 
-            public_key_index = Some(u64::from_le_bytes(
-                public_key_index_bytes.try_into().unwrap(),
-            ));
-        }
+        let (valid_after, valid_until) = if has_time_locks(version) {
+            (
+                decode_time_lock(&mut decoder)?,
+                decode_time_lock(&mut decoder)?,
+            )
+        } else {
+            (None, None)
+        };
+
+        let mut threshold_keys = None;
+        let (public_key, public_key_index) = match decoder.get_u8()? {
+            PUBLIC_KEY_TAG => (Some(decoder.get_array::<48>()?.to_vec()), None),
+            PUBLIC_KEY_INDEX_TAG => (None, Some(decoder.get_u64()?)),
+            THRESHOLD_KEYS_TAG if has_threshold_keys(version) => {
+                threshold_keys = Some(ThresholdKeys::decode(&mut decoder)?);
+                (None, None)
+            }
+            found => {
+                return Err(BlockError::new(BlockErrorKind::UnknownKeyType {
+                    found,
+                }))
+            }
+        };
 
-        let proof_of_work_len = bytes.next().unwrap().to_owned() as usize;
-        let proof_of_work = bytes.take(proof_of_work_len).collect();
+        let proof_of_work_len = decoder.get_u8()? as usize;
+        let proof_of_work = decoder.get_slice(proof_of_work_len)?.to_vec();
+        decoder.finish()?;
 
         Ok(Self {
+            version,
             coinfile_hashes,
             output_hash,
+            payload,
+            valid_after,
+            valid_until,
             public_key,
             public_key_index,
+            threshold_keys,
             proof_of_work,
         })
     }
 
-    fn hash(&self) -> Result<[u8; 64], BlockError> {
+    /// How many bytes of each hash the entry's version carries.
+    pub fn hash_len(&self) -> Result<usize, BlockError> {
+        hash_len(self.version)
+    }
+
+    /// Every form of the entry's coinfile hashes that a spend of them is
+    /// recorded under. Version 1 entries only know the version 1 form, and
+    /// version 2 entries have both, so spends of the same coinfile in either
+    /// version find each other. A hash that is already its own version 1
+    /// form only has the one.
+    pub fn coinfile_spend_forms(&self) -> Vec<[u8; 32]> {
+        let mut forms = self.coinfile_hashes.clone();
+        if self.version != ENTRY_VERSION_1 {
+            forms.extend(self.coinfile_hashes.iter().filter_map(|hash| {
+                Some(v1_hash(hash)).filter(|form| form != hash)
+            }));
+        }
+
+        forms
+    }
+
+    /// Whether the entry's `valid_after` has passed for a block at `height`,
+    /// on a chain with a median time past of `median_timestamp`.
+    pub fn is_mature(&self, height: u64, median_timestamp: u64) -> bool {
+        match self.valid_after {
+            Some(valid_after) => is_past(valid_after, height, median_timestamp),
+            None => true,
+        }
+    }
+
+    /// Whether the entry's `valid_until` has passed for a block at `height`,
+    /// on a chain with a median time past of `median_timestamp`.
+    pub fn is_expired(&self, height: u64, median_timestamp: u64) -> bool {
+        match self.valid_until {
+            Some(valid_until) => is_past(valid_until, height, median_timestamp),
+            None => false,
+        }
+    }
+
+    /// Gets the entry's public key, either from the entry itself or from the
+    /// database if the entry refers to it by index.
+    pub fn resolve_public_key(
+        &self,
+        db: &rocks::db::DB,
+    ) -> Result<PublicKey, BlockError> {
+        if let Some(public_key_bytes) = &self.public_key {
+            Ok(PublicKey::from_bytes(public_key_bytes)?)
+        } else if let Some(public_key_index) = &self.public_key_index {
+            resolve_public_key_index(db, *public_key_index)
+        } else {
+            Err(BlockError::new(BlockErrorKind::NoPublicKeyFound))
+        }
+    }
+
+    /// Gets the public keys that sign the entry: its one public key, or the
+    /// signers of its threshold keys.
+    pub fn resolve_signer_keys(
+        &self,
+        db: &rocks::db::DB,
+    ) -> Result<Vec<PublicKey>, BlockError> {
+        let key = (
+            &self.public_key,
+            self.public_key_index,
+            &self.threshold_keys,
+        );
+        match key {
+            (None, None, Some(threshold_keys)) => {
+                threshold_keys.resolve_signer_keys(db)
+            }
+            _ => Ok(vec![self.resolve_public_key(db)?]),
+        }
+    }
+
+    /// What the entry's signer signs: the id of the chain the entry is for,
+    /// then the entry's bytes. The chain id keeps an entry signed for one
+    /// network from being replayed on another.
+    ///
+    /// Versions that can be owned by threshold keys also put the signer's
+    /// public key before the entry's bytes. Every signer of one entry would
+    /// otherwise sign the same message, which lets a made up key cancel out
+    /// the others in the aggregate. Entries with a single key already carry
+    /// it, or its index, in their bytes.
+    pub fn signing_message(
+        &self,
+        chain_id: u32,
+        public_key: &PublicKey,
+    ) -> Result<Vec<u8>, BlockError> {
+        // Encoding fails for versions without a known message layout
+        let entry_bytes = self.to_bytes()?;
+
+        let mut message = chain_id.to_le_bytes().to_vec();
+        if has_threshold_keys(self.version) {
+            message.extend_from_slice(&public_key.as_bytes());
+        }
+        message.extend_from_slice(&entry_bytes);
+
+        Ok(message)
+    }
+
+    pub(crate) fn hash(&self) -> Result<[u8; 64], BlockError> {
         let to_hash = self.to_bytes()?;
 
         let hash = Blake2b::digest(&to_hash)[..].try_into().unwrap();
         Ok(hash)
     }
 
-    pub fn difficulty(&self) -> Result<usize, BlockError> {
-        let leading_zeros = {
-            let mut leading_zeros = 0;
-            for i in self.hash()?.iter() {
-                if i.leading_zeros() == 0 {
-                    break;
-                } else {
-                    leading_zeros += i.leading_zeros();
-                };
-            }
-            leading_zeros
-        };
+    /// The work the entry's proof of work shows, going by the first 32 bytes
+    /// of its hash.
+    pub fn difficulty(&self) -> Result<u64, BlockError> {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&self.hash()?[..32]);
 
-        Ok(2usize.pow(leading_zeros))
+        Ok(target::hash_work(&hash) as u64)
     }
 }
 
@@ -455,15 +803,120 @@ impl Entry {
 impl Default for Entry {
     fn default() -> Self {
         Self {
-            coinfile_hashes: vec![[0u8; 8]],
-            output_hash: [0u8; 8],
+            version: ENTRY_VERSION,
+            coinfile_hashes: vec![[0u8; 32]],
+            output_hash: [0u8; 32],
+            payload: Vec::new(),
+            valid_after: None,
+            valid_until: None,
             public_key: Some(vec![4u8; 48]),
             public_key_index: None,
+            threshold_keys: None,
             proof_of_work: vec![2u8; 4],
         }
     }
 }
 
+/// The version 1 form of a hash: its first `V1_HASH_LEN` bytes, padded with
+/// zeros.
+pub fn v1_hash(hash: &[u8; 32]) -> [u8; 32] {
+    let mut v1_hash = [0u8; 32];
+    v1_hash[..V1_HASH_LEN].copy_from_slice(&hash[..V1_HASH_LEN]);
+    v1_hash
+}
+
+fn hash_len(version: u8) -> Result<usize, BlockError> {
+    match version {
+        ENTRY_VERSION_1 => Ok(V1_HASH_LEN),
+        ENTRY_VERSION_2 | ENTRY_VERSION_3 | ENTRY_VERSION_4
+        | ENTRY_VERSION_5 => Ok(32),
+        found => Err(BlockError::new(BlockErrorKind::UnknownEntryVersion {
+            found,
+        })),
+    }
+}
+
+fn has_payload(version: u8) -> bool {
+    version >= ENTRY_VERSION_3
+}
+
+/// Gets the public key stored in the chain at `index`.
+pub(crate) fn resolve_public_key_index(
+    db: &rocks::db::DB,
+    index: u64,
+) -> Result<PublicKey, BlockError> {
+    let key = KeyType::make_key(KeyType::PublicKey, &index.to_le_bytes());
+
+    let public_key_bytes = db
+        .get(ReadOptions::default_instance(), &key)
+        .map_err(|error| {
+            debug!(%error, "public key not found");
+            BlockError::new(BlockErrorKind::NoPublicKeyFound)
+        })?;
+
+    Ok(PublicKey::from_bytes(&public_key_bytes)?)
+}
+
+fn has_threshold_keys(version: u8) -> bool {
+    version >= ENTRY_VERSION_5
+}
+
+fn has_time_locks(version: u8) -> bool {
+    version >= ENTRY_VERSION_4
+}
+
+/// Whether a block at `height`, on a chain with a median time past of
+/// `median_timestamp`, is after `time_lock`.
+fn is_past(time_lock: u64, height: u64, median_timestamp: u64) -> bool {
+    if time_lock < LOCK_TIME_THRESHOLD {
+        height > time_lock
+    } else {
+        median_timestamp > time_lock
+    }
+}
+
+fn decode_time_lock(decoder: &mut Decoder) -> Result<Option<u64>, BlockError> {
+    if decoder.get_bool()? {
+        Ok(Some(decoder.get_u64()?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn check_payload_len(payload_len: usize) -> Result<(), BlockError> {
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(BlockError::new(BlockErrorKind::PayloadTooLong {
+            maximum: MAX_PAYLOAD_SIZE,
+            found: payload_len,
+        }));
+    }
+
+    Ok(())
+}
+
+/// The bytes of `hash` an entry encodes, failing if a version 1 hash has bytes
+/// set past the ones it carries.
+fn encoded_hash(hash: &[u8; 32], hash_len: usize) -> Result<&[u8], BlockError> {
+    if hash[hash_len..].iter().any(|byte| *byte != 0) {
+        return Err(BlockError::new(BlockErrorKind::HashTooLong {
+            maximum: hash_len,
+        }));
+    }
+
+    Ok(&hash[..hash_len])
+}
+
+/// Reads a hash of `hash_len` bytes, padding it with zeros.
+fn decode_hash(
+    decoder: &mut Decoder,
+    hash_len: usize,
+) -> Result<[u8; 32], BlockError> {
+    let mut hash = [0u8; 32];
+    hash[..hash_len].copy_from_slice(decoder.get_slice(hash_len)?);
+
+    Ok(hash)
+}
+
 impl From<MempoolEntry> for Entry {
     fn from(mempool_entry: MempoolEntry) -> Self {
         mempool_entry.entry
@@ -472,7 +925,7 @@ impl From<MempoolEntry> for Entry {
 
 // An entry with a signature. Signatures aren't aggregated until they are added to a block, so
 // until then they must be a MempoolEntry.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct MempoolEntry {
     entry: Entry,
     signature: Vec<u8>,
@@ -485,6 +938,67 @@ impl MempoolEntry {
             signature,
         }
     }
+
+    /// Signs `entry` with the key that owns it, for the chain with `chain_id`.
+    pub fn sign(
+        entry: Entry,
+        chain_id: u32,
+        private_key: &PrivateKey,
+    ) -> Result<Self, BlockError> {
+        let message =
+            entry.signing_message(chain_id, &private_key.public_key())?;
+        let signature = private_key.sign(message).as_bytes();
+
+        Ok(Self::new(entry, signature))
+    }
+
+    /// Combines the partial signatures of an entry owned by threshold keys into
+    /// its signature. There must be exactly one from each of its signers.
+    pub fn combine(
+        entry: Entry,
+        partial_signatures: &[PartialSignature],
+    ) -> Result<Self, BlockError> {
+        let signers = match &entry.threshold_keys {
+            Some(threshold_keys) => &threshold_keys.signers,
+            None => {
+                return Err(BlockError::new(BlockErrorKind::NoPublicKeyFound))
+            }
+        };
+
+        for partial_signature in partial_signatures {
+            if !signers.contains(&partial_signature.signer) {
+                return Err(BlockError::new(BlockErrorKind::NotASigner {
+                    signer: partial_signature.signer,
+                }));
+            }
+        }
+
+        let mut signatures = Vec::new();
+        for signer in signers {
+            let partial_signature = partial_signatures
+                .iter()
+                .find(|partial_signature| partial_signature.signer == *signer)
+                .ok_or_else(|| {
+                    BlockError::new(BlockErrorKind::MissingPartialSignature {
+                        signer: *signer,
+                    })
+                })?;
+            signatures
+                .push(Signature::from_bytes(&partial_signature.signature)?);
+        }
+
+        let signature = bls_signatures::aggregate(&signatures)?.as_bytes();
+
+        Ok(Self::new(entry, signature))
+    }
+
+    pub fn entry(&self) -> &Entry {
+        &self.entry
+    }
+
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
 }
 
 #[test]
@@ -505,6 +1019,50 @@ fn serialization_test() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[test]
+fn header_commitment_covers_every_field() -> Result<(), Box<dyn Error>> {
+    let fields = HeaderFields {
+        version: 0x2000_0000,
+        previous_hash: [1u8; 32],
+        height: 2,
+        timestamp: 4,
+        target: 0x1d00_ffff,
+        entry_difficulty_multiplier: 7f32,
+        max_allowed_entry_difficulty: 8f32,
+        min_entry_difficulty: 9,
+        miner_address: [10u8; 32],
+    };
+    let header = BlockHeader::new(fields, [3u8; 28], 6f32, vec![11u8; 96]);
+    let seed_key = [0u8; 32];
+    let randomx_input = [12u8; 8];
+    let hash = header.calc_hash(&seed_key, &randomx_input)?;
+
+    let changes: Vec<fn(&mut BlockHeader)> = vec![
+        |header| header.version ^= 1,
+        |header| header.previous_hash[0] ^= 1,
+        |header| header.height += 1,
+        |header| header.merkle_root[0] ^= 1,
+        |header| header.timestamp += 1,
+        |header| header.target += 1,
+        |header| header.entry_difficulty += 1f32,
+        |header| header.entry_difficulty_multiplier += 1f32,
+        |header| header.max_allowed_entry_difficulty += 1f32,
+        |header| header.min_entry_difficulty += 1,
+        |header| header.miner_address[0] ^= 1,
+        |header| header.signature[0] ^= 1,
+        |header| header.signature.push(0),
+    ];
+
+    for change in changes {
+        let mut changed_header = header.clone();
+        change(&mut changed_header);
+
+        assert_ne!(changed_header.calc_hash(&seed_key, &randomx_input)?, hash);
+    }
+
+    Ok(())
+}
+
 impl From<Entry> for Vec<u8> {
     fn from(entry: Entry) -> Self {
         // Unwrap is okay as there are very few cases where serialization will
@@ -517,26 +1075,64 @@ impl From<Entry> for Vec<u8> {
 #[derive(Debug)]
 pub struct BlockError {
     kind: BlockErrorKind,
-    source: Option<Box<dyn Error>>,
+    entry_index: Option<usize>,
+    source: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl BlockError {
-    fn new(kind: BlockErrorKind) -> Self {
-        Self { kind, source: None }
+    pub(crate) fn new(kind: BlockErrorKind) -> Self {
+        Self {
+            kind,
+            entry_index: None,
+            source: None,
+        }
     }
-    fn from_source(error: Box<dyn Error>) -> Self {
+    fn from_source(error: Box<dyn Error + Send + Sync>) -> Self {
         Self {
             kind: BlockErrorKind::Other,
+            entry_index: None,
             source: Some(error),
         }
     }
+
+    /// Records which entry in the block caused the error.
+    fn at_entry(mut self, entry_index: usize) -> Self {
+        self.entry_index = Some(entry_index);
+        self
+    }
+
+    pub fn kind(&self) -> &BlockErrorKind {
+        &self.kind
+    }
+
+    /// The index of the offending entry within the block, if the error was
+    /// caused by a single entry.
+    pub fn entry_index(&self) -> Option<usize> {
+        self.entry_index
+    }
 }
 
-impl Error for BlockError {}
+impl Error for BlockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn Error + 'static))
+    }
+}
 
 impl fmt::Display for BlockError {
     fn fmt(&self, formattor: &mut fmt::Formatter) -> fmt::Result {
-        write!(formattor, "{:#?}", self)
+        if let Some(entry_index) = self.entry_index {
+            write!(formattor, "entry {}: ", entry_index)?;
+        }
+
+        write!(formattor, "{}", self.kind)?;
+
+        if let Some(source) = &self.source {
+            write!(formattor, ": {}", source)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -548,19 +1144,37 @@ impl From<RandomxError> for BlockError {
 
 impl From<rocks::error::Error> for BlockError {
     fn from(error: rocks::error::Error) -> Self {
-        BlockError::from_source(Box::new(error))
+        // Rocks errors hold a raw pointer, so they aren't Send or Sync
+        BlockError::from_source(Box::new(io::Error::other(error.to_string())))
     }
 }
 
-impl From<rmp_serde::encode::Error> for BlockError {
-    fn from(error: rmp_serde::encode::Error) -> Self {
-        BlockError::from_source(Box::new(error))
-    }
-}
+impl From<EncodingError> for BlockError {
+    fn from(error: EncodingError) -> Self {
+        let kind = match error.kind() {
+            EncodingErrorKind::UnexpectedEnd { needed, found } => {
+                BlockErrorKind::Truncated {
+                    needed: *needed,
+                    found: *found,
+                }
+            }
+            EncodingErrorKind::TrailingBytes { found } => {
+                BlockErrorKind::TrailingBytes { found: *found }
+            }
+            EncodingErrorKind::LengthTooBig { found } => {
+                BlockErrorKind::LengthTooBig {
+                    maximum: u32::MAX as usize,
+                    found: *found,
+                }
+            }
+            _ => BlockErrorKind::InvalidEncoding,
+        };
 
-impl From<rmp_serde::decode::Error> for BlockError {
-    fn from(error: rmp_serde::decode::Error) -> Self {
-        BlockError::from_source(Box::new(error))
+        BlockError {
+            kind,
+            entry_index: None,
+            source: Some(Box::new(error)),
+        }
     }
 }
 
@@ -570,11 +1184,142 @@ impl From<bls_signatures::Error> for BlockError {
     }
 }
 
-#[derive(Debug)]
-enum BlockErrorKind {
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum BlockErrorKind {
     NoPublicKeyFound,
+    /// An entry has more than one of a public key, a public key index and
+    /// threshold keys.
+    MultipleKeys,
     InvalidSignature,
-    TooManyCoinfileHashes,
-    PoWTooLong,
+    /// Two entries in a block have the same signing message.
+    DuplicateMessage,
+    TooManyCoinfileHashes { maximum: usize, found: usize },
+    PoWTooLong { maximum: usize, found: usize },
+    /// The bytes ended before the value being decoded did.
+    Truncated { needed: usize, found: usize },
+    /// There were bytes left over after decoding.
+    TrailingBytes { found: usize },
+    UnknownKeyType { found: u8 },
+    /// An entry's version has no known encoding.
+    UnknownEntryVersion { found: u8 },
+    /// A hash has more bytes than the entry's version carries.
+    HashTooLong { maximum: usize },
+    /// An entry has a payload, but its version can't carry one.
+    PayloadNotSupported,
+    /// A payload is longer than any entry can encode.
+    PayloadTooLong { maximum: usize, found: usize },
+    /// An entry has time locks, but its version can't carry them.
+    TimeLocksNotSupported,
+    /// An entry has threshold keys, but its version can't carry them.
+    ThresholdKeysNotSupported,
+    TooManyThresholdKeys { maximum: usize, found: usize },
+    /// The threshold is zero or more than the number of keys.
+    InvalidThreshold { threshold: u8, keys: usize },
+    /// The signers of threshold keys aren't in increasing order, or aren't
+    /// all among the keys.
+    InvalidSigners,
+    NotEnoughSigners { threshold: u8, found: usize },
+    /// Threshold keys name the same key more than once.
+    DuplicateThresholdKey,
+    /// The key is not one of the entry's signers.
+    NotASigner { signer: u8 },
+    /// One of the entry's signers has no partial signature to combine.
+    MissingPartialSignature { signer: u8 },
+    /// A length prefix is longer than the value it's for can ever be.
+    LengthTooBig { maximum: usize, found: usize },
+    InvalidEncoding,
     Other,
 }
+
+impl fmt::Display for BlockErrorKind {
+    fn fmt(&self, formattor: &mut fmt::Formatter) -> fmt::Result {
+        use BlockErrorKind::*;
+
+        match self {
+            NoPublicKeyFound => write!(formattor, "no public key found"),
+            MultipleKeys => write!(formattor, "more than one key found"),
+            InvalidSignature => write!(formattor, "invalid signature"),
+            DuplicateMessage => {
+                write!(formattor, "signing message appears more than once")
+            }
+            TooManyCoinfileHashes { maximum, found } => write!(
+                formattor,
+                "{} coinfile hashes, the maximum is {}",
+                found, maximum
+            ),
+            PoWTooLong { maximum, found } => write!(
+                formattor,
+                "proof of work is {} bytes, the maximum is {}",
+                found, maximum
+            ),
+            Truncated { needed, found } => write!(
+                formattor,
+                "truncated, needed {} more bytes but only {} are left",
+                needed, found
+            ),
+            TrailingBytes { found } => {
+                write!(formattor, "{} bytes left over after decoding", found)
+            }
+            UnknownKeyType { found } => {
+                write!(formattor, "unknown key type {:#04x}", found)
+            }
+            UnknownEntryVersion { found } => {
+                write!(formattor, "unknown entry version {}", found)
+            }
+            HashTooLong { maximum } => write!(
+                formattor,
+                "hash is longer than the entry's {} bytes",
+                maximum
+            ),
+            PayloadNotSupported => {
+                write!(formattor, "the entry's version has no payload")
+            }
+            PayloadTooLong { maximum, found } => write!(
+                formattor,
+                "payload is {} bytes, the maximum is {}",
+                found, maximum
+            ),
+            TimeLocksNotSupported => {
+                write!(formattor, "the entry's version has no time locks")
+            }
+            ThresholdKeysNotSupported => {
+                write!(formattor, "the entry's version has no threshold keys")
+            }
+            TooManyThresholdKeys { maximum, found } => write!(
+                formattor,
+                "{} threshold keys, the maximum is {}",
+                found, maximum
+            ),
+            InvalidThreshold { threshold, keys } => write!(
+                formattor,
+                "threshold {} isn't valid for {} keys",
+                threshold, keys
+            ),
+            InvalidSigners => write!(formattor, "invalid threshold signers"),
+            NotEnoughSigners { threshold, found } => write!(
+                formattor,
+                "{} signers, the threshold is {}",
+                found, threshold
+            ),
+            DuplicateThresholdKey => {
+                write!(formattor, "threshold keys name a key more than once")
+            }
+            NotASigner { signer } => {
+                write!(formattor, "key {} isn't a signer of the entry", signer)
+            }
+            MissingPartialSignature { signer } => write!(
+                formattor,
+                "no partial signature from signer {}",
+                signer
+            ),
+            LengthTooBig { maximum, found } => write!(
+                formattor,
+                "length {} is more than the maximum {}",
+                found, maximum
+            ),
+            InvalidEncoding => write!(formattor, "invalid encoding"),
+            Other => write!(formattor, "other error"),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+/// The top three bits of a block version that signals for deployments. The
+/// other bits are free for signalling, one bit per deployment.
+pub const VERSIONBITS_TOP_BITS: u32 = 0x2000_0000;
+
+/// The bits of a block version that must equal `VERSIONBITS_TOP_BITS`.
+pub const VERSIONBITS_TOP_MASK: u32 = 0xe000_0000;
+
+/// The highest bit a deployment can signal with.
+pub const MAX_DEPLOYMENT_BIT: u8 = 28;
+
+/// A change to the consensus rules, which comes into force once enough
+/// miners signal that they follow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DeploymentId {
+    /// Never has any rules, only used to test activation.
+    TestDummy,
+    /// Entries can be owned by threshold keys.
+    ThresholdKeys,
+}
+
+/// When and how a deployment is signalled for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deployment {
+    pub id: DeploymentId,
+    /// The version bit blocks set to signal for the deployment.
+    pub bit: u8,
+    /// The first height at which signalling counts, rounded up to the next
+    /// signalling window.
+    pub start_height: u64,
+    /// The height after which the deployment fails if it isn't locked in,
+    /// also rounded up to the next signalling window.
+    pub timeout_height: u64,
+}
+
+impl Deployment {
+    pub fn new(
+        id: DeploymentId,
+        bit: u8,
+        start_height: u64,
+        timeout_height: u64,
+    ) -> Self {
+        assert!(bit <= MAX_DEPLOYMENT_BIT, "deployment bit out of range");
+
+        Self {
+            id,
+            bit,
+            start_height,
+            timeout_height,
+        }
+    }
+
+    /// Whether a block with `version` signals for the deployment.
+    pub fn is_signalled_by(&self, version: u32) -> bool {
+        version & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS
+            && version & (1 << self.bit) != 0
+    }
+
+    /// The state of the deployment for a signalling window starting at
+    /// `window_start`, given its state in the window before and how many
+    /// blocks in that window signalled for it.
+    pub fn next_state(
+        &self,
+        state: ThresholdState,
+        window_start: u64,
+        signals: u64,
+        threshold: u64,
+    ) -> ThresholdState {
+        use ThresholdState::*;
+
+        match state {
+            Defined if window_start >= self.timeout_height => Failed,
+            Defined if window_start >= self.start_height => Started,
+            // Locking in wins over timing out in the same window
+            Started if signals >= threshold => LockedIn,
+            Started if window_start >= self.timeout_height => Failed,
+            LockedIn => Active,
+            state => state,
+        }
+    }
+}
+
+/// Where a deployment is in its activation. The state only changes at the
+/// start of a signalling window, and `Active` and `Failed` are final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThresholdState {
+    /// Signalling hasn't started yet.
+    Defined,
+    /// Blocks signal for the deployment.
+    Started,
+    /// Enough blocks signalled, the deployment is active from the next window.
+    LockedIn,
+    /// The deployment's rules are enforced.
+    Active,
+    /// The deployment timed out before enough blocks signalled.
+    Failed,
+}
@@ -0,0 +1,144 @@
+use crate::target::U256;
+
+/// How many blocks `SimpleAverage` looks back by default.
+pub const DEFAULT_SIMPLE_AVERAGE_WINDOW: usize = 750;
+
+/// How many blocks `Lwma` looks back by default.
+pub const DEFAULT_LWMA_WINDOW: usize = 90;
+
+// The longest solve time `Lwma` counts, in block times. Keeps one block with a
+// timestamp far in the future from raising the target much.
+const LWMA_MAX_SOLVE_TIME: u64 = 6;
+
+/// The timestamp and target of a block, which is all a difficulty algorithm
+/// sees of it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetargetBlock {
+    pub timestamp: u64,
+    pub target: U256,
+}
+
+/// Picks the target of the next block from the blocks before it.
+pub trait DifficultyAlgorithm: Send + Sync {
+    /// A short name for logs.
+    fn name(&self) -> &'static str;
+
+    /// How many of the latest blocks `next_target` wants.
+    fn window(&self) -> usize;
+
+    /// The target for the next block, given up to `window` of the latest
+    /// blocks, oldest first. There are always at least two blocks.
+    fn next_target(&self, blocks: &[RetargetBlock], block_time: u64) -> U256;
+}
+
+/// Scales the average target by how far the average solve time is from the
+/// block time. Every block in the window counts the same, so it is steady but
+/// slow to follow changes in hash rate.
+pub struct SimpleAverage {
+    window: usize,
+}
+
+impl SimpleAverage {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(2),
+        }
+    }
+}
+
+impl Default for SimpleAverage {
+    fn default() -> Self {
+        Self::new(DEFAULT_SIMPLE_AVERAGE_WINDOW)
+    }
+}
+
+impl DifficultyAlgorithm for SimpleAverage {
+    fn name(&self) -> &'static str {
+        "simple average"
+    }
+
+    fn window(&self) -> usize {
+        self.window
+    }
+
+    fn next_target(&self, blocks: &[RetargetBlock], block_time: u64) -> U256 {
+        let first = blocks.first().expect("at least two blocks");
+        let last = blocks.last().expect("at least two blocks");
+
+        // The solve times add up to the time between the first and last
+        // blocks, and there is one less of them than there are blocks
+        let solve_times = blocks.len() as u64 - 1;
+        let total_time = last.timestamp.saturating_sub(first.timestamp).max(1);
+
+        average_target(blocks).mul_div(total_time, solve_times * block_time)
+    }
+}
+
+/// A linearly weighted moving average: recent solve times count for more,
+/// so the target follows changes in hash rate within a few dozen blocks.
+///
+/// Timestamps that go backwards count as one second after the block before,
+/// and solve times are capped, so one bad timestamp can't move the target far.
+pub struct Lwma {
+    window: usize,
+}
+
+impl Lwma {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(2),
+        }
+    }
+}
+
+impl Default for Lwma {
+    fn default() -> Self {
+        Self::new(DEFAULT_LWMA_WINDOW)
+    }
+}
+
+impl DifficultyAlgorithm for Lwma {
+    fn name(&self) -> &'static str {
+        "lwma"
+    }
+
+    fn window(&self) -> usize {
+        self.window
+    }
+
+    fn next_target(&self, blocks: &[RetargetBlock], block_time: u64) -> U256 {
+        let max_solve_time = LWMA_MAX_SOLVE_TIME * block_time;
+
+        let mut previous_timestamp = blocks[0].timestamp;
+        let mut weighted_solve_time = 0u64;
+        for (weight, block) in blocks.iter().enumerate().skip(1) {
+            let timestamp = block.timestamp.max(previous_timestamp + 1);
+            let solve_time =
+                (timestamp - previous_timestamp).min(max_solve_time);
+            previous_timestamp = timestamp;
+
+            weighted_solve_time += solve_time * weight as u64;
+        }
+
+        // Solve time n has weight n, so the weights add up to n(n + 1) / 2
+        let solve_times = blocks.len() as u64 - 1;
+        let total_weight = solve_times * (solve_times + 1) / 2;
+
+        // A burst of very fast blocks can't lower the target more than tenfold
+        let expected_solve_time = total_weight * block_time;
+        let weighted_solve_time =
+            weighted_solve_time.max(expected_solve_time / 10);
+
+        average_target(&blocks[1..])
+            .mul_div(weighted_solve_time, expected_solve_time)
+    }
+}
+
+/// The mean of the blocks' targets. Each one is divided first so the total
+/// can't overflow.
+fn average_target(blocks: &[RetargetBlock]) -> U256 {
+    blocks.iter().fold(U256::ZERO, |total, block| {
+        let share = block.target.mul_div(1, blocks.len() as u64);
+        total.checked_add(share).unwrap_or(U256::MAX)
+    })
+}
@@ -0,0 +1,233 @@
+use std::convert::{TryFrom, TryInto};
+use std::{error::Error, fmt};
+
+/// Writes values in the canonical binary encoding used for blocks, headers
+/// and the blockchain info.
+///
+/// Integers and floats are written little endian at their full width, fixed
+/// size byte arrays as they are, and variable length byte strings are prefixed
+/// with their length as a u32. Nothing is optional or packed, so every value
+/// has exactly one encoding.
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(value as u8);
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a fixed size array, without a length.
+    pub fn put_array(&mut self, array: &[u8]) {
+        self.bytes.extend_from_slice(array);
+    }
+
+    /// Writes the length of a byte string or list as a u32, failing if it
+    /// doesn't fit in one.
+    pub fn put_len(&mut self, length: usize) -> Result<(), EncodingError> {
+        let length = u32::try_from(length).map_err(|_| {
+            let kind = EncodingErrorKind::LengthTooBig { found: length };
+            EncodingError::new(kind)
+        })?;
+        self.put_u32(length);
+
+        Ok(())
+    }
+
+    /// Writes a variable length byte string, prefixed with its length.
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), EncodingError> {
+        self.put_len(bytes.len())?;
+        self.bytes.extend_from_slice(bytes);
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads values written by `Encoder`, rejecting anything that isn't the one
+/// canonical encoding of a value.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, EncodingError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, EncodingError> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            found => Err(EncodingError::new(EncodingErrorKind::InvalidBool {
+                found,
+            })),
+        }
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, EncodingError> {
+        Ok(u32::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, EncodingError> {
+        Ok(u64::from_le_bytes(self.get_array()?))
+    }
+
+    /// Reads a float, rejecting NaN as it has many encodings and never
+    /// compares equal to anything, and negative zero as it equals zero.
+    pub fn get_f32(&mut self) -> Result<f32, EncodingError> {
+        let value = f32::from_le_bytes(self.get_array()?);
+        if value.is_nan() {
+            return Err(EncodingError::new(EncodingErrorKind::NaN));
+        }
+        if value == 0f32 && value.is_sign_negative() {
+            return Err(EncodingError::new(EncodingErrorKind::NegativeZero));
+        }
+
+        Ok(value)
+    }
+
+    pub fn get_array<const N: usize>(
+        &mut self,
+    ) -> Result<[u8; N], EncodingError> {
+        let array = self
+            .take(N)?
+            .try_into()
+            .expect("took exactly as many bytes as the array holds");
+
+        Ok(array)
+    }
+
+    /// Reads a length prefixed byte string. The length can't be more than the
+    /// bytes that are left.
+    pub fn get_bytes(&mut self) -> Result<Vec<u8>, EncodingError> {
+        let length = self.get_u32()? as usize;
+
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /// Reads `length` bytes, for byte strings whose length is known some other
+    /// way.
+    pub fn get_slice(
+        &mut self,
+        length: usize,
+    ) -> Result<&'a [u8], EncodingError> {
+        self.take(length)
+    }
+
+    /// Finishes decoding, failing if there are bytes left over.
+    pub fn finish(self) -> Result<(), EncodingError> {
+        if !self.bytes.is_empty() {
+            return Err(EncodingError::new(EncodingErrorKind::TrailingBytes {
+                found: self.bytes.len(),
+            }));
+        }
+
+        Ok(())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], EncodingError> {
+        if length > self.bytes.len() {
+            return Err(EncodingError::new(EncodingErrorKind::UnexpectedEnd {
+                needed: length,
+                found: self.bytes.len(),
+            }));
+        }
+
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+}
+
+#[derive(Debug)]
+pub struct EncodingError {
+    kind: EncodingErrorKind,
+}
+
+impl EncodingError {
+    pub(crate) fn new(kind: EncodingErrorKind) -> Self {
+        Self { kind }
+    }
+
+    pub fn kind(&self) -> &EncodingErrorKind {
+        &self.kind
+    }
+}
+
+impl Error for EncodingError {}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum EncodingErrorKind {
+    UnexpectedEnd { needed: usize, found: usize },
+    TrailingBytes { found: usize },
+    InvalidBool { found: u8 },
+    NaN,
+    NegativeZero,
+    /// A value decoded fine, but is out of range for what it's decoded into.
+    OutOfRange,
+    /// A length being encoded doesn't fit in a u32.
+    LengthTooBig { found: usize },
+}
+
+impl fmt::Display for EncodingErrorKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        use EncodingErrorKind::*;
+
+        match self {
+            UnexpectedEnd { needed, found } => write!(
+                formatter,
+                "needed {} more bytes, only {} are left",
+                needed, found
+            ),
+            TrailingBytes { found } => {
+                write!(formatter, "{} bytes left over after decoding", found)
+            }
+            InvalidBool { found } => {
+                write!(formatter, "{} is not a valid bool", found)
+            }
+            NaN => write!(formatter, "float is NaN"),
+            NegativeZero => write!(formatter, "float is negative zero"),
+            OutOfRange => write!(formatter, "value is out of range"),
+            LengthTooBig { found } => {
+                write!(formatter, "length {} doesn't fit in a u32", found)
+            }
+        }
+    }
+}
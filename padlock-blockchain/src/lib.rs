@@ -1,7 +1,7 @@
 extern crate bls_signatures;
 extern crate merkle_tree;
 extern crate randomx_bindings;
-extern crate rmp_serde;
+extern crate rayon;
 
 extern crate serde;
 use serde::{Deserialize, Serialize};
@@ -9,37 +9,119 @@ use serde::{Deserialize, Serialize};
 extern crate rocks;
 use rocks::prelude::*;
 
+extern crate tracing;
+use tracing::{debug, info, trace, warn};
+
 pub mod block;
-use block::{Block, BlockHeader};
+use block::{Block, BlockError, BlockErrorKind, BlockHeader, Entry};
+
+pub mod deployment;
+use deployment::{DeploymentId, ThresholdState};
+
+pub mod difficulty;
+use difficulty::RetargetBlock;
+
+pub mod encoding;
+use encoding::{Decoder, Encoder, EncodingError, EncodingErrorKind};
+
+pub mod mempool;
+pub mod miner;
+
+pub mod params;
+use params::NetworkParams;
+
+pub mod randomx;
+
+pub mod reward;
+use reward::{Reward, RewardAccount};
+
+pub mod signatures;
+use signatures::{SignatureCache, SignedMessages};
+
+pub mod target;
+use target::U256;
 
-use std::{error::Error, fmt, time::SystemTime};
+pub mod template;
 
-const BLOCK_TIME: f32 = 90f32; // target interval between blocks in seconds
+pub mod threshold;
+
+use rayon::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::{convert::TryInto, error::Error, fmt, io, time::SystemTime};
 
 // The amount of blocks to consider when getting averages, such as average difficulty
 const PREVIOUS_BLOCKS_TO_CONSIDER: usize = 750;
 
+// How many of the latest blocks the median time past is taken over
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// The lowest the minimum entry difficulty can be retargeted to.
+pub const MIN_ENTRY_DIFFICULTY_FLOOR: u64 = 16;
+
+// The minimum entry difficulty is this fraction of the average entry
+// difficulty of a block, so a block can still fit this many minimal entries
+// before reaching the average.
+const MIN_ENTRY_DIFFICULTY_DIVISOR: f32 = 256f32;
+
+/// The version of the layout blocks and entries are stored in. A database
+/// written with any other version can't be read, and has to be deleted so the
+/// chain can be synced again.
+///
+/// Version 1 is the canonical block encoding with versioned entries. Entries
+/// from before had no version byte, and can't be told apart from versioned
+/// ones, so chains holding them start over instead of being migrated.
+pub const STORAGE_VERSION: u32 = 1;
+
+// The database keys the blockchain info and storage version are kept under
+const BLOCKCHAIN_INFO_KEY: &[u8] = b"blockchain_info";
+const STORAGE_VERSION_KEY: &[u8] = b"storage_version";
+
 pub struct Blockchain {
     pub db_dir: String,
     pub info: BlockchainInfo,
     pub db: rocks::db::DB,
+    pub params: NetworkParams,
+    /// Signatures already verified by the mempool, which blocks' signatures
+    /// are checked against before falling back to a pairing.
+    pub signature_cache: SignatureCache,
+    // Deployment states, keyed by the hash of the last block before the
+    // signalling window they're for, so a reorganisation can't make them stale
+    deployment_states: Mutex<HashMap<(DeploymentId, Vec<u8>), ThresholdState>>,
 }
 
 impl Blockchain {
     pub fn new(db_dir: &str) -> Result<Self, Box<dyn Error>> {
+        Self::with_params(db_dir, NetworkParams::default())
+    }
+
+    /// Opens the blockchain in `db_dir`, following the rules of `params`.
+    pub fn with_params(
+        db_dir: &str,
+        params: NetworkParams,
+    ) -> Result<Self, Box<dyn Error>> {
 		let options = Options::default().map_db_options(|db_opt| db_opt.create_if_missing(true));
 		let db = DB::open(options, &db_dir)?;
 
-        let info = match db.get(ReadOptions::default_instance(), b"blockchain_info") {
+        let info = match db.get(ReadOptions::default_instance(), BLOCKCHAIN_INFO_KEY) {
             Ok(blockchain_info_bytes) => {
-				rmp_serde::from_slice(&blockchain_info_bytes)?
+                check_storage_version(&db)?;
+				BlockchainInfo::from_bytes(&blockchain_info_bytes)?
             }
-            Err(_) => {
+            Err(error) => {
+                info!(%error, db_dir, "no blockchain info, starting new chain");
+
                 let blockchain_info = BlockchainInfo::default();
+                db.put(
+                    WriteOptions::default_instance(),
+                    STORAGE_VERSION_KEY,
+                    &STORAGE_VERSION.to_le_bytes(),
+                )?;
                 db.put(
 					WriteOptions::default_instance(),
-                    b"blockchain_info",
-                    &rmp_serde::to_vec(&blockchain_info)?,
+                    BLOCKCHAIN_INFO_KEY,
+                    &blockchain_info.to_bytes(),
                 )?;
                 blockchain_info
             }
@@ -49,17 +131,23 @@ impl Blockchain {
             db_dir: String::from(db_dir),
             info,
             db,
+            params,
+            signature_cache: SignatureCache::default(),
+            deployment_states: Mutex::new(HashMap::new()),
         })
     }
 
     pub fn get_block(&self, hash: &[u8]) -> Result<Block, BlockchainError> {
-		match self.db.get(ReadOptions::default_instance(), hash) {
+        let key = KeyType::make_key(KeyType::Block, hash);
+
+		match self.db.get(ReadOptions::default_instance(), &key) {
 			Ok(block_bytes) => {
 				let block = Block::from_bytes(&block_bytes)?;
 		       	Ok(block)
 			}
 			
-			Err(_) => {
+			Err(error) => {
+				trace!(%error, hash = %hex(hash), "block not found");
 				Err(BlockchainError::new(
            		 BlockchainErrorKind::BlockDoesntExist,
        	 		))
@@ -68,88 +156,584 @@ impl Blockchain {
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<(), BlockchainError> {
-        if self.get_block(&block.hash).is_ok() {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockAlreadyExists,
+        let span = validation_span(&block.header, &block.hash);
+        let _enter = span.enter();
+
+        self.validate_header(
+            &block.header,
+            &block.randomx_input,
+            &block.hash,
+        )?;
+        self.validate_body(&block)?;
+        self.check_deployment_rules(&block)?;
+
+        self.connect_block(block)
+    }
+
+    /// Adds a batch of consecutive blocks, such as during initial sync.
+    ///
+    /// The checks that don't depend on the state of the chain (the RandomX
+    /// hash and the whole body) are run on every block in parallel first, with
+    /// all of their signatures checked together in one batch. The headers are
+    /// then checked against the chain and the blocks connected one at a time
+    /// in height order. Stops at the first invalid block, leaving every block
+    /// before it connected.
+    pub fn add_blocks(
+        &mut self,
+        mut blocks: Vec<Block>,
+    ) -> Result<(), BlockchainError> {
+        blocks.sort_by_key(|block| block.header.height);
+
+        // A block's seed block can be earlier in the same batch
+        let seed_keys: Vec<Result<[u8; 32], BlockchainError>> = blocks
+            .iter()
+            .map(|block| {
+                let seed_height = randomx::seed_height(block.header.height);
+                if seed_height <= self.info.height {
+                    return self.seed_key(block.header.height);
+                }
+
+                blocks
+                    .iter()
+                    .find(|seed_block| seed_block.header.height == seed_height)
+                    .map(|seed_block| seed_block.hash)
+                    .ok_or_else(|| {
+                        BlockchainError::new(
+                            BlockchainErrorKind::CantFindHashFromHeight {
+                                height: seed_height,
+                            },
+                        )
+                    })
+            })
+            .collect();
+
+        let context_free_results: Vec<Result<SignedMessages, BlockchainError>> =
+            blocks
+                .par_iter()
+                .zip(seed_keys)
+                .map(|(block, seed_key)| {
+                    let span = validation_span(&block.header, &block.hash);
+                    let _enter = span.enter();
+
+                    self.check_header_pow(
+                        &block.header,
+                        &seed_key?,
+                        &block.randomx_input,
+                        &block.hash,
+                    )?;
+                    self.check_body_contents(block)?;
+                    block
+                        .signed_messages(&self.db, self.params.chain_id)
+                        .map_err(|error| {
+                            signature_rejection(&block.hash, error)
+                        })
+                })
+                .collect();
+
+        // Every signature is checked at once, and only if one of them is
+        // invalid are they checked one at a time to find which
+        let signed_messages: Vec<&SignedMessages> = context_free_results
+            .iter()
+            .filter_map(|result| result.as_ref().ok())
+            .collect();
+        let signatures_valid = signatures::verify_batch(&signed_messages);
+        trace!(
+            blocks = signed_messages.len(),
+            signatures_valid,
+            "checked batch of block signatures"
+        );
+
+        for (block, context_free_result) in
+            blocks.into_iter().zip(context_free_results)
+        {
+            let span = validation_span(&block.header, &block.hash);
+            let _enter = span.enter();
+
+            self.check_header_contextual(&block.header, &block.hash)?;
+            let signed_messages = context_free_result?;
+            if !signatures_valid
+                && !signed_messages.verify(&self.signature_cache)
+            {
+                let error = BlockError::new(BlockErrorKind::InvalidSignature);
+                return Err(signature_rejection(&block.hash, error));
+            }
+            self.check_deployment_rules(&block)?;
+            self.check_entry_time_locks(&block)?;
+            self.check_coinfile_spends(&block)?;
+
+            self.connect_block(block)?;
+        }
+
+        Ok(())
+    }
+
+    /// Validates a block header against the top of the chain, without needing
+    /// the block's entries. Checks the header's height, previous hash,
+    /// difficulty target, timestamp and entry difficulty limit, then that
+    /// `hash` is the RandomX hash of the header and `randomx_input` under the
+    /// header's seed key, and has enough work.
+    ///
+    /// Cheap rules are checked before the RandomX hash, so most bad headers
+    /// are rejected without hashing.
+    pub fn validate_header(
+        &self,
+        header: &BlockHeader,
+        randomx_input: &[u8],
+        hash: &[u8; 32],
+    ) -> Result<(), BlockchainError> {
+        self.check_header_contextual(header, hash)?;
+
+        let seed_key = self.seed_key(header.height)?;
+        self.check_header_pow(header, &seed_key, randomx_input, hash)
+    }
+
+    /// The RandomX key for a block at `height`, which is the hash of the block
+    /// at its seed height.
+    pub fn seed_key(&self, height: u64) -> Result<[u8; 32], BlockchainError> {
+        let seed_height = randomx::seed_height(height);
+        if seed_height == 0 {
+            return Ok(randomx::GENESIS_SEED_KEY);
+        }
+
+        self.get_block_hash(seed_height)?
+            .as_slice()
+            .try_into()
+            .map_err(|_| BlockchainError::new(BlockchainErrorKind::Other))
+    }
+
+    /// The state of a deployment for a block at `height`, which can be at most
+    /// one above the top of the chain.
+    ///
+    /// The state is worked out one signalling window at a time, from the last
+    /// window whose state is known, by counting the blocks in each window that
+    /// signal for the deployment.
+    pub fn deployment_state(
+        &self,
+        id: DeploymentId,
+        height: u64,
+    ) -> Result<ThresholdState, BlockchainError> {
+        let deployment = match self.params.deployment(id) {
+            Some(deployment) => deployment,
+            None => return Ok(ThresholdState::Defined),
+        };
+        let window = self.params.signalling_window;
+
+        // Find the windows whose states aren't known yet, newest first
+        let mut unknown_windows = Vec::new();
+        let mut window_start = height - height % window;
+        let mut state = ThresholdState::Defined;
+        while window_start > 0 {
+            let key = (id, self.get_block_hash(window_start - 1)?);
+            if let Some(known_state) = self.cached_deployment_state(&key) {
+                state = known_state;
+                break;
+            }
+
+            unknown_windows.push((window_start, key));
+            window_start -= window;
+        }
+
+        for (window_start, key) in unknown_windows.into_iter().rev() {
+            let mut signals = 0;
+            if state == ThresholdState::Started {
+                // There is no block at height 0
+                for height in (window_start - window).max(1)..window_start {
+                    let hash = self.get_block_hash(height)?;
+                    let header = self.get_block_header(&hash)?;
+                    if deployment.is_signalled_by(header.version) {
+                        signals += 1;
+                    }
+                }
+            }
+
+            state = deployment.next_state(
+                state,
+                window_start,
+                signals,
+                self.params.activation_threshold,
+            );
+            self.deployment_states
+                .lock()
+                .expect("deployment states lock poisoned")
+                .insert(key, state);
+        }
+
+        Ok(state)
+    }
+
+    /// The version for a new block at `height`, signalling for every
+    /// deployment that is started or locked in.
+    pub fn block_version(&self, height: u64) -> Result<u32, BlockchainError> {
+        let mut version = deployment::VERSIONBITS_TOP_BITS;
+        for deployment in &self.params.deployments {
+            let state = self.deployment_state(deployment.id, height)?;
+            if matches!(
+                state,
+                ThresholdState::Started | ThresholdState::LockedIn
+            ) {
+                version |= 1 << deployment.bit;
+            }
+        }
+
+        Ok(version)
+    }
+
+    fn cached_deployment_state(
+        &self,
+        key: &(DeploymentId, Vec<u8>),
+    ) -> Option<ThresholdState> {
+        self.deployment_states
+            .lock()
+            .expect("deployment states lock poisoned")
+            .get(key)
+            .copied()
+    }
+
+    /// Checks that every entry's time locks allow it in the block. They are
+    /// compared with the median time past of the block's parent, so this can
+    /// only run once the chain is at it.
+    fn check_entry_time_locks(
+        &self,
+        block: &Block,
+    ) -> Result<(), BlockchainError> {
+        let height = block.header.height;
+        let median_timestamp = self.info.past_median_timestamp;
+
+        for (entry_index, entry) in block.entries.iter().enumerate() {
+            if !entry.is_mature(height, median_timestamp) {
+                return Err(reject(
+                    &block.hash,
+                    BlockchainErrorKind::EntryNotYetValid { entry_index },
+                ));
+            }
+
+            if entry.is_expired(height, median_timestamp) {
+                return Err(reject(
+                    &block.hash,
+                    BlockchainErrorKind::EntryExpired { entry_index },
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that no entry spends a coinfile that is already spent, either by
+    /// the chain or by another entry in the block. Every form of each hash is
+    /// checked, so a version 1 entry can't spend a coinfile again by its short
+    /// hash.
+    fn check_coinfile_spends(
+        &self,
+        block: &Block,
+    ) -> Result<(), BlockchainError> {
+        let mut spent_in_block = HashSet::new();
+
+        for (entry_index, entry) in block.entries.iter().enumerate() {
+            for coinfile_hash in entry.coinfile_spend_forms() {
+                if !spent_in_block.insert(coinfile_hash)
+                    || self.is_coinfile_spent(&coinfile_hash)?
+                {
+                    return Err(reject(
+                        &block.hash,
+                        BlockchainErrorKind::CoinfileAlreadySpent {
+                            entry_index,
+                            coinfile_hash,
+                        },
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the rules that depend on which deployments are active at the
+    /// block's height. Deployment states depend on the blocks before, so this
+    /// can only run once the chain is at the block's parent.
+    fn check_deployment_rules(
+        &self,
+        block: &Block,
+    ) -> Result<(), BlockchainError> {
+        if !self.allows_threshold_keys(block.header.height)? {
+            let threshold_entry = block
+                .entries
+                .iter()
+                .position(|entry| entry.threshold_keys.is_some());
+            if let Some(entry_index) = threshold_entry {
+                return Err(reject(
+                    &block.hash,
+                    BlockchainErrorKind::ThresholdKeysNotActive {
+                        entry_index,
+                    },
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether entries in a block at `height`, which can be at most one above
+    /// the top of the chain, can be owned by threshold keys. They can once
+    /// the `ThresholdKeys` deployment is active.
+    pub fn allows_threshold_keys(
+        &self,
+        height: u64,
+    ) -> Result<bool, BlockchainError> {
+        let state = self.deployment_state(DeploymentId::ThresholdKeys, height)?;
+        Ok(state == ThresholdState::Active)
+    }
+
+    /// Validates a block's entries against its header, which must already
+    /// have been accepted by `validate_header`. Checks the version, payload,
+    /// work, time locks and spent coinfiles of each entry, the entry
+    /// difficulty, merkle root, block size and aggregated signature.
+    pub fn validate_body(&self, block: &Block) -> Result<(), BlockchainError> {
+        self.check_body_contents(block)?;
+        self.check_entry_time_locks(block)?;
+        self.check_coinfile_spends(block)?;
+
+        let chain_id = self.params.chain_id;
+        block
+            .check_signature(&self.db, chain_id, &self.signature_cache)
+            .map_err(|error| signature_rejection(&block.hash, error))
+    }
+
+    /// Checks every body rule that doesn't depend on the chain, except the
+    /// aggregated signature.
+    fn check_body_contents(
+        &self,
+        block: &Block,
+    ) -> Result<(), BlockchainError> {
+        let mut block_payload_size = 0;
+
+        // The header's minimum was already checked against the chain
+        for (entry_index, entry) in block.entries.iter().enumerate() {
+            let height = block.header.height;
+            if !self.params.is_entry_version_allowed(entry.version, height) {
+                return Err(reject(
+                    &block.hash,
+                    BlockchainErrorKind::EntryVersionNotAllowed {
+                        entry_index,
+                        version: entry.version,
+                    },
+                ));
+            }
+
+            let payload_size = entry.payload.len();
+            if payload_size > self.params.max_entry_payload_size {
+                return Err(reject(
+                    &block.hash,
+                    BlockchainErrorKind::EntryPayloadTooBig {
+                        entry_index,
+                        maximum: self.params.max_entry_payload_size,
+                        found: payload_size,
+                    },
+                ));
+            }
+            block_payload_size += payload_size;
+
+            let difficulty = entry.difficulty()?;
+            if difficulty < block.header.min_entry_difficulty {
+                return Err(reject(
+                    &block.hash,
+                    BlockchainErrorKind::EntryNotEnoughWork {
+                        entry_index,
+                        minimum: block.header.min_entry_difficulty,
+                        found: difficulty,
+                    },
+                ));
+            }
+        }
+
+        if block_payload_size > self.params.max_block_payload_size {
+            return Err(reject(
+                &block.hash,
+                BlockchainErrorKind::BlockPayloadTooBig {
+                    maximum: self.params.max_block_payload_size,
+                    found: block_payload_size,
+                },
+            ));
+        }
+
+        let entry_difficulty = block.entry_difficulty()?;
+        if block.header.entry_difficulty != entry_difficulty {
+            return Err(reject(
+                &block.hash,
+                BlockchainErrorKind::BlockEntryDifficultyWrong {
+                    expected: entry_difficulty,
+                    found: block.header.entry_difficulty,
+                },
+            ));
+        }
+
+        let merkle_root = block.calc_merkle_root();
+        if merkle_root != block.header.merkle_root {
+            return Err(reject(
+                &block.hash,
+                BlockchainErrorKind::InvalidMerkleRoot {
+                    expected: merkle_root,
+                    found: block.header.merkle_root,
+                },
             ));
         }
 
-        if block.header.height > self.info.height + 1 {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::SkippedBlock,
+        let block_size = block.to_bytes()?.len();
+        if block_size > self.info.block_size_cap {
+            return Err(reject(
+                &block.hash,
+                BlockchainErrorKind::BlockTooBig {
+                    maximum: self.info.block_size_cap,
+                    found: block_size,
+                },
             ));
         }
 
-        if block.header.height < self.info.height + 1 {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockNotAtTop,
+        Ok(())
+    }
+
+    /// Checks the header rules that depend on the current state of the chain.
+    fn check_header_contextual(
+        &self,
+        header: &BlockHeader,
+        hash: &[u8; 32],
+    ) -> Result<(), BlockchainError> {
+        if self.get_block(hash).is_ok() {
+            return Err(reject(hash, BlockchainErrorKind::BlockAlreadyExists));
+        }
+
+        if header.height > self.info.height + 1 {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::SkippedBlock {
+                    expected: self.info.height + 1,
+                    found: header.height,
+                },
             ));
         }
 
-        if block.header.previous_hash != self.info.top_block_hash {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockPreviousHashWrong,
+        if header.height < self.info.height + 1 {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockNotAtTop {
+                    expected: self.info.height + 1,
+                    found: header.height,
+                },
             ));
         }
 
-        if block.header.difficulty_target != self.info.difficulty {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockTargetDifficultyWrong,
+        if header.previous_hash != self.info.top_block_hash {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockPreviousHashWrong {
+                    expected: self.info.top_block_hash,
+                    found: header.previous_hash,
+                },
             ));
         }
 
-        if block.header.timestamp < self.info.past_median_timestamp {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockTimestampTooEarly,
+        if header.target != self.info.target {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockTargetWrong {
+                    expected: self.info.target,
+                    found: header.target,
+                },
             ));
         }
 
-        if block.header.timestamp > self.info.network_adjusted_time + 3600 {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockInFuture,
+        if header.timestamp < self.info.past_median_timestamp {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockTimestampTooEarly {
+                    minimum: self.info.past_median_timestamp,
+                    found: header.timestamp,
+                },
             ));
         }
 
-        if block.difficulty()? < self.info.difficulty {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockNotEnoughWork,
+        if header.timestamp > self.info.network_adjusted_time + 3600 {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockInFuture {
+                    maximum: self.info.network_adjusted_time + 3600,
+                    found: header.timestamp,
+                },
             ));
         }
 
-        if block.header.entry_difficulty != block.entry_difficulty()? {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockEntryDifficultyWrong,
+        if header.version & deployment::VERSIONBITS_TOP_MASK
+            != deployment::VERSIONBITS_TOP_BITS
+        {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockVersionInvalid {
+                    found: header.version,
+                },
             ));
         }
 
-        if block.header.max_allowed_entry_difficulty
+        if header.max_allowed_entry_difficulty
             != self.info.max_allowed_entry_difficulty
         {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::BlockMaxAllowedEntryDifficultyWrong,
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockMaxAllowedEntryDifficultyWrong {
+                    expected: self.info.max_allowed_entry_difficulty,
+                    found: header.max_allowed_entry_difficulty,
+                },
             ));
         }
 
-        if !block.is_merkle_root_valid() {
-            return Err(BlockchainError::new(
-                BlockchainErrorKind::InvalidMerkleRoot,
+        if header.min_entry_difficulty != self.info.min_entry_difficulty {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockMinEntryDifficultyWrong {
+                    expected: self.info.min_entry_difficulty,
+                    found: header.min_entry_difficulty,
+                },
             ));
         }
 
-        let block_bytes = block.to_bytes()?;
-        if block_bytes.len() > self.info.block_size_cap {
-            return Err(BlockchainError::new(BlockchainErrorKind::BlockTooBig));
+        if !header.meets_target(hash) {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::BlockNotEnoughWork {
+                    target: header.effective_target().to_be_bytes(),
+                    found: *hash,
+                },
+            ));
         }
 
-        if block.check_signature(&self.db).is_err() {
-            return Err(BlockchainError::new(BlockchainErrorKind::InvalidSignature))
-        }
+        Ok(())
+    }
 
-        let calculated_hash = block.calc_hash()?;
-        if calculated_hash != block.hash {
-            return Err(BlockchainError::new(BlockchainErrorKind::InvalidHash));
+    /// Checks that `hash` is the RandomX hash of the header and its input.
+    fn check_header_pow(
+        &self,
+        header: &BlockHeader,
+        seed_key: &[u8; 32],
+        randomx_input: &[u8],
+        hash: &[u8; 32],
+    ) -> Result<(), BlockchainError> {
+        let calculated_hash = header.calc_hash(seed_key, randomx_input)?;
+        if &calculated_hash != hash {
+            return Err(reject(
+                hash,
+                BlockchainErrorKind::InvalidHash {
+                    expected: calculated_hash,
+                    found: *hash,
+                },
+            ));
         }
 
+        Ok(())
+    }
+
+    /// Writes an already validated block to the database and moves the top of
+    /// the chain to it.
+    fn connect_block(&mut self, block: Block) -> Result<(), BlockchainError> {
+        let block_bytes = block.to_bytes()?;
+
         self.info.height += 1;
         self.info.top_block_hash = block.hash;
         self.info.is_empty = false;
@@ -159,21 +743,45 @@ impl Blockchain {
 
         self.add_block_hash(&block)?;
         self.add_block_header(&block)?;
+        self.add_spent_coinfiles(&block)?;
+        self.add_reward(&block)?;
 
         self.update_median_timestamp()?;
         self.update_difficulty()?;
         self.update_entry_difficulty_limits()?;
 
+        info!(entries = block.entries.len(), "connected block");
+
         Ok(())
     }
 
-    /// removes the top block from the blockchain
-    pub fn del_top_block(&mut self) -> Result<(), BlockchainError> {
+    /// Whether an entry in the chain has already spent the coinfile. Only
+    /// finds spends recorded under the same form of its hash, so every form
+    /// in `Entry::coinfile_spend_forms` needs checking.
+    pub fn is_coinfile_spent(
+        &self,
+        coinfile_hash: &[u8; 32],
+    ) -> Result<bool, BlockchainError> {
+        let key = KeyType::make_key(KeyType::SpentCoinfile, coinfile_hash);
+
+        match self.db.get(ReadOptions::default_instance(), &key) {
+            Ok(_) => Ok(true),
+            Err(error) if error.is_not_found() => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// removes the top block from the blockchain, returning it so its entries
+    /// can be put back in the mempool
+    pub fn del_top_block(&mut self) -> Result<Block, BlockchainError> {
         let block_hash = self.get_block_hash(self.info.height)?;
         let block_header = self.get_block_header(&block_hash)?;
+        let block = self.get_block(&block_hash)?;
 
         self.del_block_hash(block_header.height)?;
         self.del_block_header(&block_hash)?;
+        self.del_spent_coinfiles(&block)?;
+        self.del_reward(&block)?;
 
         self.info.top_block_hash = block_header.previous_hash;
         self.info.height -= 1;
@@ -185,7 +793,13 @@ impl Blockchain {
         self.update_difficulty()?;
         self.update_entry_difficulty_limits()?;
 
-        Ok(())
+        info!(
+            height = block_header.height,
+            hash = %hex(&block_hash),
+            "disconnected top block"
+        );
+
+        Ok(block)
     }
 
     /// Adds the block's hash to the database, where the key is the block's
@@ -203,7 +817,7 @@ impl Blockchain {
     // Gets a blocks hash from it's height
     fn get_block_hash(
         &self,
-        height: usize,
+        height: u64,
     ) -> Result<Vec<u8>, BlockchainError> {
         let key =
             KeyType::make_key(KeyType::BlockHeight, &height.to_le_bytes());
@@ -213,13 +827,16 @@ impl Blockchain {
 				Ok((&hash).to_vec())
 			}
 			
-			Err(_) => {
-				Err(BlockchainError::new(BlockchainErrorKind::CantFindHashFromHeight))
+			Err(error) => {
+				debug!(%error, height, "no block hash at height");
+				Err(BlockchainError::new(
+					BlockchainErrorKind::CantFindHashFromHeight { height },
+				))
 			}
 		}
     }
 
-    fn del_block_hash(&self, height: usize) -> Result<(), BlockchainError> {
+    fn del_block_hash(&self, height: u64) -> Result<(), BlockchainError> {
         let key =
             KeyType::make_key(KeyType::BlockHeight, &height.to_le_bytes());
         self.db.delete(WriteOptions::default_instance(), &key)?;
@@ -233,20 +850,25 @@ impl Blockchain {
     ) -> Result<BlockHeader, BlockchainError> {
         let key = KeyType::make_key(KeyType::BlockHeader, hash);
         
-		if let Ok(header_bytes) = &self.db.get(ReadOptions::default_instance(), &key) {
-			let header = rmp_serde::from_slice(&header_bytes)?;
-
-	       	 Ok(header)
-		} else {
-			Err(BlockchainError::new(
-				BlockchainErrorKind::BlockHeaderDoesntExist,
-			))
+		match self.db.get(ReadOptions::default_instance(), &key) {
+			Ok(header_bytes) => {
+				let header = BlockHeader::from_bytes(&header_bytes)?;
+
+	       	 	Ok(header)
+			}
+
+			Err(error) => {
+				debug!(%error, hash = %hex(hash), "block header not found");
+				Err(BlockchainError::new(
+					BlockchainErrorKind::BlockHeaderDoesntExist,
+				))
+			}
 		}
     }
 
     fn add_block_header(&self, block: &Block) -> Result<(), BlockchainError> {
         let key = KeyType::make_key(KeyType::BlockHeader, &block.hash);
-        let header_bytes = rmp_serde::to_vec(&block.header)?;
+        let header_bytes = block.header.to_bytes()?;
 
         self.db.put(WriteOptions::default_instance(), &key, &header_bytes)?;
 
@@ -260,42 +882,198 @@ impl Blockchain {
         Ok(())
     }
 
+    /// Marks every coinfile the block's entries spend as spent, recording the
+    /// hash of the block that spent it.
+    fn add_spent_coinfiles(
+        &self,
+        block: &Block,
+    ) -> Result<(), BlockchainError> {
+        for entry in &block.entries {
+            for coinfile_hash in &self.spent_coinfile_forms(block, entry) {
+                let key =
+                    KeyType::make_key(KeyType::SpentCoinfile, coinfile_hash);
+                self.db.put(
+                    WriteOptions::default_instance(),
+                    &key,
+                    &block.hash,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn del_spent_coinfiles(
+        &self,
+        block: &Block,
+    ) -> Result<(), BlockchainError> {
+        for entry in &block.entries {
+            for coinfile_hash in &self.spent_coinfile_forms(block, entry) {
+                let key =
+                    KeyType::make_key(KeyType::SpentCoinfile, coinfile_hash);
+                self.db.delete(WriteOptions::default_instance(), &key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The forms of the entry's coinfile hashes that its spends are recorded
+    /// under. The version 1 forms of version 2 hashes are only needed while
+    /// version 1 entries, which would look for them, are still valid.
+    fn spent_coinfile_forms(
+        &self,
+        block: &Block,
+        entry: &Entry,
+    ) -> Vec<[u8; 32]> {
+        if block.header.height < self.params.entry_v1_end_height {
+            entry.coinfile_spend_forms()
+        } else {
+            entry.coinfile_hashes.clone()
+        }
+    }
+
+    /// The total of every reward paid to `miner_address` by blocks in the
+    /// chain.
+    pub fn balance(
+        &self,
+        miner_address: &[u8; 32],
+    ) -> Result<u64, BlockchainError> {
+        Ok(self.get_reward_account(miner_address)?.balance)
+    }
+
+    /// Every reward paid to `miner_address` by blocks in the chain, oldest
+    /// first.
+    pub fn reward_history(
+        &self,
+        miner_address: &[u8; 32],
+    ) -> Result<Vec<Reward>, BlockchainError> {
+        let account = self.get_reward_account(miner_address)?;
+
+        (0..account.reward_count)
+            .map(|index| {
+                let key = reward_key(miner_address, index);
+                let reward_bytes =
+                    self.db.get(ReadOptions::default_instance(), &key)?;
+
+                Ok(Reward::from_bytes(&reward_bytes)?)
+            })
+            .collect()
+    }
+
+    fn get_reward_account(
+        &self,
+        miner_address: &[u8; 32],
+    ) -> Result<RewardAccount, BlockchainError> {
+        let key = KeyType::make_key(KeyType::RewardAccount, miner_address);
+
+        // Addresses that were never paid have no account
+        match self.db.get(ReadOptions::default_instance(), &key) {
+            Ok(account_bytes) => Ok(RewardAccount::from_bytes(&account_bytes)?),
+            Err(error) if error.is_not_found() => Ok(RewardAccount::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn put_reward_account(
+        &self,
+        miner_address: &[u8; 32],
+        account: RewardAccount,
+    ) -> Result<(), BlockchainError> {
+        let key = KeyType::make_key(KeyType::RewardAccount, miner_address);
+        self.db.put(
+            WriteOptions::default_instance(),
+            &key,
+            &account.to_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Credits the block's subsidy to its miner address.
+    fn add_reward(&self, block: &Block) -> Result<(), BlockchainError> {
+        let miner_address = &block.header.miner_address;
+        let mut account = self.get_reward_account(miner_address)?;
+
+        let reward = Reward {
+            height: block.header.height,
+            block_hash: block.hash,
+            amount: self.params.subsidy.reward(block.header.height),
+        };
+
+        let key = reward_key(miner_address, account.reward_count);
+        self.db.put(
+            WriteOptions::default_instance(),
+            &key,
+            &reward.to_bytes(),
+        )?;
+
+        account.balance = account
+            .balance
+            .checked_add(reward.amount)
+            .ok_or_else(|| BlockchainError::new(BlockchainErrorKind::Other))?;
+        account.reward_count += 1;
+        self.put_reward_account(miner_address, account)
+    }
+
+    /// Takes back the reward `add_reward` paid for the block. Blocks are only
+    /// disconnected from the top, so it is always the address's latest reward.
+    fn del_reward(&self, block: &Block) -> Result<(), BlockchainError> {
+        let miner_address = &block.header.miner_address;
+        let mut account = self.get_reward_account(miner_address)?;
+
+        let latest = account
+            .reward_count
+            .checked_sub(1)
+            .ok_or_else(|| BlockchainError::new(BlockchainErrorKind::Other))?;
+        let key = reward_key(miner_address, latest);
+        let reward_bytes = self.db.get(ReadOptions::default_instance(), &key)?;
+        let reward = Reward::from_bytes(&reward_bytes)?;
+        if reward.block_hash != block.hash {
+            return Err(BlockchainError::new(BlockchainErrorKind::Other));
+        }
+
+        self.db.delete(WriteOptions::default_instance(), &key)?;
+
+        account.balance -= reward.amount;
+        account.reward_count = latest;
+        self.put_reward_account(miner_address, account)
+    }
+
     fn get_previous_n_block_headers(
         &self,
         amount: usize,
     ) -> Result<Vec<BlockHeader>, BlockchainError> {
         let mut block_headers: Vec<BlockHeader> = Vec::new();
 
-        for i in 0..amount as isize {
-            let block_index = self.info.height as isize - i;
+        for i in 0..amount as i64 {
+            let block_index = self.info.height as i64 - i;
             if block_index < 1 {
                 break;
             }
 
-            let block_hash = self.get_block_hash(block_index as usize)?;
+            let block_hash = self.get_block_hash(block_index as u64)?;
             let block_header = self.get_block_header(&block_hash)?;
             block_headers.push(block_header)
         }
         Ok(block_headers)
     }
 
-    /// The median timstamp is the median timestamp of the previous 21 blocks. If the current
-    /// blockchain height is less than 11, it will choose the timestamp of the first block.
+    /// The median time past is the median timestamp of the latest
+    /// `MEDIAN_TIME_SPAN` blocks, or of every block while there are fewer.
     fn update_median_timestamp(&mut self) -> Result<(), BlockchainError> {
         if self.info.height < 1 {
             return Ok(());
         }
 
-        let mut block_index = self.info.height as isize - 11;
-        if block_index < 1 {
-            block_index = 1;
-        }
+        let mut timestamps: Vec<u64> = self
+            .get_previous_n_block_headers(MEDIAN_TIME_SPAN)?
+            .iter()
+            .map(|header| header.timestamp)
+            .collect();
+        timestamps.sort_unstable();
 
-        let block_hash = self.get_block_hash(block_index as usize)?;
-
-        let block_header = self.get_block_header(&block_hash)?;
-
-        self.info.past_median_timestamp = block_header.timestamp;
+        self.info.past_median_timestamp = timestamps[timestamps.len() / 2];
 
         Ok(())
     }
@@ -305,40 +1083,29 @@ impl Blockchain {
             return Ok(());
         }
 
-        let block_headers = self.get_previous_n_block_headers(PREVIOUS_BLOCKS_TO_CONSIDER)?;
-
-        let average_difficulty = {
-            let mut total = 0u128;
-
-            for header in &block_headers {
-                total += header.difficulty_target as u128;
-            }
-
-            total as f32 / block_headers.len() as f32
-        };
-
-        println!("average difficulty: {}", average_difficulty);
-
-        let average_block_time = {
-            let mut total = 0i128;
-
-            for (i, header) in block_headers.iter().enumerate().skip(1) {
-                let last_header = &block_headers[i - 1];
-                let block_time =
-                    last_header.timestamp as i128 - header.timestamp as i128;
-                total += block_time
-            }
-
-            total as f32 / block_headers.len() as f32
-        };
-
-        println!("average block time: {}", average_block_time);
-
-        let network_hash_rate = average_difficulty / average_block_time;
-
-        println!("network hash rate: {}", network_hash_rate);
-
-        self.info.difficulty = network_hash_rate * BLOCK_TIME;
+        let algorithm = &self.params.difficulty_algorithm;
+        let block_headers =
+            self.get_previous_n_block_headers(algorithm.window())?;
+
+        // The headers come newest first
+        let blocks: Vec<RetargetBlock> = block_headers
+            .iter()
+            .rev()
+            .map(|header| RetargetBlock {
+                timestamp: header.timestamp,
+                target: U256::from_compact(header.target),
+            })
+            .collect();
+
+        let new_target = algorithm.next_target(&blocks, self.params.block_time);
+        self.info.target = new_target.to_compact();
+
+        debug!(
+            algorithm = algorithm.name(),
+            target = ?new_target,
+            difficulty = target::difficulty_from_target(new_target),
+            "retargeted difficulty"
+        );
 
         Ok(())
     }
@@ -353,12 +1120,13 @@ impl Blockchain {
         let block_headers = self.get_previous_n_block_headers(PREVIOUS_BLOCKS_TO_CONSIDER)?;
 
         let average_difficulty = {
-            let mut total = 0u128;
+            let mut total = 0f64;
             for header in &block_headers {
-                total += header.difficulty_target as u128;
+                let target = U256::from_compact(header.target);
+                total += target::difficulty_from_target(target);
             }
 
-            total as f32 / block_headers.len() as f32
+            (total / block_headers.len() as f64) as f32
         };
 
         let average_entry_difficulty = {
@@ -370,30 +1138,45 @@ impl Blockchain {
             total as f32 / block_headers.len() as f32
         };
 
-        println!("average entry difficulty: {}", average_entry_difficulty);
-
         self.info.entry_difficulty_multiplier =
             (average_difficulty * 0.05) / average_entry_difficulty;
 
         self.info.max_allowed_entry_difficulty =
             average_entry_difficulty * 2f32;
 
+        self.info.min_entry_difficulty = ((average_entry_difficulty
+            / MIN_ENTRY_DIFFICULTY_DIVISOR)
+            as u64)
+            .max(MIN_ENTRY_DIFFICULTY_FLOOR);
+
+        debug!(
+            average_entry_difficulty,
+            entry_difficulty_multiplier = self.info.entry_difficulty_multiplier,
+            max_allowed_entry_difficulty =
+                self.info.max_allowed_entry_difficulty,
+            min_entry_difficulty = self.info.min_entry_difficulty,
+            "retargeted entry difficulty limits"
+        );
+
         Ok(())
     }
 }
 
 /// Contains information about the state of the blockchain
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub struct BlockchainInfo {
     pub is_empty: bool,
     pub top_block_hash: [u8; 32],
     pub past_median_timestamp: u64,
     pub network_adjusted_time: u64,
-    pub difficulty: f32,
+    /// The compact target of the next block
+    pub target: u32,
     pub entry_difficulty_multiplier: f32,
     pub max_allowed_entry_difficulty: f32,
+    /// The least difficulty each entry in the next block must have
+    pub min_entry_difficulty: u64,
     pub block_size_cap: usize,
-    pub height: usize,
+    pub height: u64,
 }
 
 impl Default for BlockchainInfo {
@@ -406,21 +1189,153 @@ impl Default for BlockchainInfo {
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
-            difficulty: 256f32,
+            target: target::target_from_difficulty(256f64).to_compact(),
             entry_difficulty_multiplier: 0.005,
             max_allowed_entry_difficulty: 4096f32,
+            min_entry_difficulty: MIN_ENTRY_DIFFICULTY_FLOOR,
             block_size_cap: 250000,
             height: 0,
         }
     }
 }
 
+impl BlockchainInfo {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Destructured so that a new field can't be left out by accident
+        let BlockchainInfo {
+            is_empty,
+            top_block_hash,
+            past_median_timestamp,
+            network_adjusted_time,
+            target,
+            entry_difficulty_multiplier,
+            max_allowed_entry_difficulty,
+            min_entry_difficulty,
+            block_size_cap,
+            height,
+        } = self;
+
+        let mut encoder = Encoder::new();
+        encoder.put_bool(*is_empty);
+        encoder.put_array(top_block_hash);
+        encoder.put_u64(*past_median_timestamp);
+        encoder.put_u64(*network_adjusted_time);
+        encoder.put_u32(*target);
+        encoder.put_f32(*entry_difficulty_multiplier);
+        encoder.put_f32(*max_allowed_entry_difficulty);
+        encoder.put_u64(*min_entry_difficulty);
+        encoder.put_u64(*block_size_cap as u64);
+        encoder.put_u64(*height);
+
+        encoder.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let mut decoder = Decoder::new(bytes);
+
+        let info = BlockchainInfo {
+            is_empty: decoder.get_bool()?,
+            top_block_hash: decoder.get_array()?,
+            past_median_timestamp: decoder.get_u64()?,
+            network_adjusted_time: decoder.get_u64()?,
+            target: decoder.get_u32()?,
+            entry_difficulty_multiplier: decoder.get_f32()?,
+            max_allowed_entry_difficulty: decoder.get_f32()?,
+            min_entry_difficulty: decoder.get_u64()?,
+            block_size_cap: decoder.get_u64()?.try_into().map_err(|_| {
+                EncodingError::new(EncodingErrorKind::OutOfRange)
+            })?,
+            height: decoder.get_u64()?,
+        };
+        decoder.finish()?;
+
+        Ok(info)
+    }
+}
+
+/// Checks that `db` was written with `STORAGE_VERSION`. Databases from before
+/// the version was recorded have none, which is taken as version 0.
+fn check_storage_version(db: &DB) -> Result<(), BlockchainError> {
+    let found =
+        match db.get(ReadOptions::default_instance(), STORAGE_VERSION_KEY) {
+            Ok(version_bytes) => {
+                let mut decoder = Decoder::new(&version_bytes);
+                let version = decoder.get_u32()?;
+                decoder.finish()?;
+                version
+            }
+            Err(error) if error.is_not_found() => 0,
+            Err(error) => return Err(error.into()),
+        };
+
+    if found != STORAGE_VERSION {
+        return Err(BlockchainError::new(
+            BlockchainErrorKind::StorageVersionMismatch {
+                expected: STORAGE_VERSION,
+                found,
+            },
+        ));
+    }
+
+    Ok(())
+}
+
+fn validation_span(header: &BlockHeader, hash: &[u8; 32]) -> tracing::Span {
+    tracing::debug_span!(
+        "validate_block",
+        height = header.height,
+        hash = %hex(hash),
+    )
+}
+
+/// Logs the rule a block broke and turns it into an error carrying the block's
+/// hash. Called from inside the `validate_block` span, so the event carries the
+/// block's height and hash.
+fn reject(block_hash: &[u8; 32], kind: BlockchainErrorKind) -> BlockchainError {
+    warn!(reason = %kind, "rejected block");
+
+    let mut error = BlockchainError::new(kind);
+    error.block_hash = Some(*block_hash);
+    error
+}
+
+/// Rejects a block whose signature couldn't be checked or is invalid, pointing
+/// at the entry at fault if there is one.
+fn signature_rejection(
+    block_hash: &[u8; 32],
+    error: BlockError,
+) -> BlockchainError {
+    debug!(%error, "signature check failed");
+
+    reject(
+        block_hash,
+        BlockchainErrorKind::InvalidSignature {
+            entry_index: error.entry_index(),
+        },
+    )
+    .with_source(error)
+}
+
+/// Formats bytes, such as block hashes, as lowercase hex for log output.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The key of a miner address's reward at `index`, counting from its first.
+fn reward_key(miner_address: &[u8; 32], index: u64) -> Vec<u8> {
+    let key = [&miner_address[..], &index.to_be_bytes()].concat();
+    KeyType::make_key(KeyType::Reward, &key)
+}
+
 /// Every key starts with a byte that determines what type of key it is.
 enum KeyType {
     Block,
     BlockHeader,
     BlockHeight,
     PublicKey,
+    SpentCoinfile,
+    RewardAccount,
+    Reward,
 }
 
 impl KeyType {
@@ -437,79 +1352,364 @@ impl KeyType {
             &Self::BlockHeader => 0x02,
             &Self::BlockHeight => 0x03,
             &Self::PublicKey => 0x04,
+            &Self::SpentCoinfile => 0x05,
+            &Self::RewardAccount => 0x06,
+            &Self::Reward => 0x07,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct BlockchainError {
-    kind: BlockchainErrorKind,
-    source: Option<Box<dyn Error>>,
+    // Boxed as some kinds carry hashes, which would make every result large
+    kind: Box<BlockchainErrorKind>,
+    block_hash: Option<[u8; 32]>,
+    source: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl BlockchainError {
     fn new(kind: BlockchainErrorKind) -> Self {
-        Self { kind, source: None }
+        Self {
+            kind: Box::new(kind),
+            block_hash: None,
+            source: None,
+        }
     }
 
-    fn from_source(error: Box<dyn Error>) -> Self {
+    fn from_source(error: Box<dyn Error + Send + Sync>) -> Self {
         Self {
-            kind: BlockchainErrorKind::Other,
+            kind: Box::new(BlockchainErrorKind::Other),
+            block_hash: None,
             source: Some(error),
         }
     }
+
+    fn with_source<E>(mut self, error: E) -> Self
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        self.source = Some(Box::new(error));
+        self
+    }
+
+    /// Why the operation failed. For rejected blocks this says which rule was
+    /// broken, along with what was expected and what the block contained.
+    pub fn kind(&self) -> &BlockchainErrorKind {
+        &self.kind
+    }
+
+    /// The hash of the block that was rejected, if the error came from
+    /// validating a block.
+    pub fn block_hash(&self) -> Option<&[u8; 32]> {
+        self.block_hash.as_ref()
+    }
+
+    /// The index of the offending entry within the rejected block, if a
+    /// single entry broke the rule.
+    pub fn entry_index(&self) -> Option<usize> {
+        use BlockchainErrorKind::*;
+
+        match self.kind() {
+            EntryNotEnoughWork { entry_index, .. }
+            | EntryVersionNotAllowed { entry_index, .. }
+            | EntryPayloadTooBig { entry_index, .. }
+            | EntryNotYetValid { entry_index }
+            | EntryExpired { entry_index }
+            | ThresholdKeysNotActive { entry_index }
+            | CoinfileAlreadySpent { entry_index, .. } => Some(*entry_index),
+            InvalidSignature { entry_index }
+            | InvalidBlock { entry_index, .. } => *entry_index,
+            _ => None,
+        }
+    }
 }
 
-impl Error for BlockchainError {}
+impl Error for BlockchainError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn Error + 'static))
+    }
+}
 
 impl fmt::Display for BlockchainError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "{:?}", self)
+        write!(formatter, "{}", self.kind)?;
+
+        if let Some(block_hash) = &self.block_hash {
+            write!(formatter, " (block {})", hex(block_hash))?;
+        }
+
+        if let Some(source) = &self.source {
+            write!(formatter, ": {}", source)?;
+        }
+
+        Ok(())
     }
 }
 
 impl From<rocks::error::Error> for BlockchainError {
     fn from(error: rocks::error::Error) -> Self {
-        BlockchainError::from_source(Box::new(error))
+        // Rocks errors hold a raw pointer, so they aren't Send or Sync
+        BlockchainError::from_source(Box::new(io::Error::other(
+            error.to_string(),
+        )))
     }
 }
 
-impl From<rmp_serde::encode::Error> for BlockchainError {
-    fn from(error: rmp_serde::encode::Error) -> Self {
-        BlockchainError::from_source(Box::new(error))
-    }
-}
-
-impl From<rmp_serde::decode::Error> for BlockchainError {
-    fn from(error: rmp_serde::decode::Error) -> Self {
+impl From<EncodingError> for BlockchainError {
+    fn from(error: EncodingError) -> Self {
         BlockchainError::from_source(Box::new(error))
     }
 }
 
 impl From<block::BlockError> for BlockchainError {
     fn from(error: block::BlockError) -> Self {
-        BlockchainError::from_source(Box::new(error))
+        // RandomX, database and BLS failures are ours, not the block's
+        let kind = match error.kind() {
+            block::BlockErrorKind::Other => BlockchainErrorKind::Other,
+            kind => BlockchainErrorKind::InvalidBlock {
+                kind: kind.clone(),
+                entry_index: error.entry_index(),
+            },
+        };
+
+        BlockchainError::new(kind).with_source(error)
     }
 }
 
-#[derive(Debug)]
-enum BlockchainErrorKind {
+/// The reasons a blockchain operation can fail. Most variants are the rules a
+/// block can break, and carry what was expected alongside what was found.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum BlockchainErrorKind {
     BlockDoesntExist,
-    SkippedBlock,
-    BlockNotAtTop,
-    BlockTimestampTooEarly,
-    BlockTooBig,
+    SkippedBlock { expected: u64, found: u64 },
+    BlockNotAtTop { expected: u64, found: u64 },
+    BlockTimestampTooEarly { minimum: u64, found: u64 },
+    BlockTooBig { maximum: usize, found: usize },
     BlockAlreadyExists,
-    BlockNotEnoughWork,
-    InvalidHash,
-    BlockPreviousHashWrong,
-    BlockTargetDifficultyWrong,
-    BlockInFuture,
-    InvalidMerkleRoot,
-    CantFindHashFromHeight,
+    BlockNotEnoughWork { target: [u8; 32], found: [u8; 32] },
+    InvalidHash { expected: [u8; 32], found: [u8; 32] },
+    BlockPreviousHashWrong { expected: [u8; 32], found: [u8; 32] },
+    BlockTargetWrong { expected: u32, found: u32 },
+    BlockInFuture { maximum: u64, found: u64 },
+    InvalidMerkleRoot { expected: [u8; 28], found: [u8; 28] },
+    CantFindHashFromHeight { height: u64 },
     BlockHeaderDoesntExist,
-    BlockEntryDifficultyWrong,
-    BlockMaxAllowedEntryDifficultyWrong,
-    InvalidSignature,
+    BlockEntryDifficultyWrong { expected: f32, found: f32 },
+    BlockMaxAllowedEntryDifficultyWrong { expected: f32, found: f32 },
+    BlockMinEntryDifficultyWrong { expected: u64, found: u64 },
+    BlockVersionInvalid { found: u32 },
+    EntryNotEnoughWork {
+        entry_index: usize,
+        minimum: u64,
+        found: u64,
+    },
+    InvalidSignature { entry_index: Option<usize> },
+    /// An entry's version isn't valid at the block's height.
+    EntryVersionNotAllowed { entry_index: usize, version: u8 },
+    /// An entry's payload is longer than the network allows.
+    EntryPayloadTooBig {
+        entry_index: usize,
+        maximum: usize,
+        found: usize,
+    },
+    /// A block's entries have more payload bytes together than the network
+    /// allows.
+    BlockPayloadTooBig { maximum: usize, found: usize },
+    /// An entry's `valid_after` hasn't passed at the block.
+    EntryNotYetValid { entry_index: usize },
+    /// An entry's `valid_until` has passed at the block.
+    EntryExpired { entry_index: usize },
+    /// An entry is owned by threshold keys before they are active.
+    ThresholdKeysNotActive { entry_index: usize },
+    /// An entry spends a coinfile that the chain, or an earlier entry in the
+    /// block, already spent.
+    CoinfileAlreadySpent {
+        entry_index: usize,
+        coinfile_hash: [u8; 32],
+    },
+    /// A block template was requested, but no pending entry fits in a block.
+    NoPendingEntries,
+    /// The database was written with another `STORAGE_VERSION`. It has to be
+    /// deleted, and the chain synced again.
+    StorageVersionMismatch { expected: u32, found: u32 },
+    /// The block itself couldn't be processed, such as an entry that can't be
+    /// serialized.
+    InvalidBlock {
+        kind: block::BlockErrorKind,
+        entry_index: Option<usize>,
+    },
     Other,
-}
\ No newline at end of file
+}
+
+impl fmt::Display for BlockchainErrorKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        use BlockchainErrorKind::*;
+
+        match self {
+            BlockDoesntExist => write!(formatter, "block doesn't exist"),
+            SkippedBlock { expected, found } => write!(
+                formatter,
+                "block height {} skips ahead of expected height {}",
+                found, expected
+            ),
+            BlockNotAtTop { expected, found } => write!(
+                formatter,
+                "block height {} is below expected height {}",
+                found, expected
+            ),
+            BlockTimestampTooEarly { minimum, found } => write!(
+                formatter,
+                "block timestamp {} is before the past median timestamp {}",
+                found, minimum
+            ),
+            BlockTooBig { maximum, found } => write!(
+                formatter,
+                "block is {} bytes, the cap is {} bytes",
+                found, maximum
+            ),
+            BlockAlreadyExists => write!(formatter, "block already exists"),
+            BlockNotEnoughWork { target, found } => write!(
+                formatter,
+                "block hash {} is above the target {}",
+                hex(found),
+                hex(target)
+            ),
+            InvalidHash { expected, found } => write!(
+                formatter,
+                "block hash {} doesn't match calculated hash {}",
+                hex(found),
+                hex(expected)
+            ),
+            BlockPreviousHashWrong { expected, found } => write!(
+                formatter,
+                "previous hash {} doesn't match top block {}",
+                hex(found),
+                hex(expected)
+            ),
+            BlockTargetWrong { expected, found } => write!(
+                formatter,
+                "target {:#010x} doesn't match expected {:#010x}",
+                found, expected
+            ),
+            BlockInFuture { maximum, found } => write!(
+                formatter,
+                "block timestamp {} is later than the maximum {}",
+                found, maximum
+            ),
+            InvalidMerkleRoot { expected, found } => write!(
+                formatter,
+                "merkle root {} doesn't match calculated root {}",
+                hex(found),
+                hex(expected)
+            ),
+            CantFindHashFromHeight { height } => {
+                write!(formatter, "no block hash at height {}", height)
+            }
+            BlockHeaderDoesntExist => {
+                write!(formatter, "block header doesn't exist")
+            }
+            BlockEntryDifficultyWrong { expected, found } => write!(
+                formatter,
+                "entry difficulty {} doesn't match calculated {}",
+                found, expected
+            ),
+            BlockMaxAllowedEntryDifficultyWrong { expected, found } => write!(
+                formatter,
+                "max allowed entry difficulty {} doesn't match expected {}",
+                found, expected
+            ),
+            BlockMinEntryDifficultyWrong { expected, found } => write!(
+                formatter,
+                "min entry difficulty {} doesn't match expected {}",
+                found, expected
+            ),
+            BlockVersionInvalid { found } => {
+                write!(formatter, "block version {:#010x} is invalid", found)
+            }
+            EntryNotEnoughWork {
+                entry_index,
+                minimum,
+                found,
+            } => write!(
+                formatter,
+                "entry {} difficulty {} is below the minimum {}",
+                entry_index, found, minimum
+            ),
+            InvalidSignature { entry_index: Some(entry_index) } => write!(
+                formatter,
+                "invalid signature, caused by entry {}",
+                entry_index
+            ),
+            InvalidSignature { entry_index: None } => {
+                write!(formatter, "invalid aggregated signature")
+            }
+            EntryVersionNotAllowed {
+                entry_index,
+                version,
+            } => write!(
+                formatter,
+                "entry {} has version {}, which isn't valid at this height",
+                entry_index, version
+            ),
+            EntryPayloadTooBig {
+                entry_index,
+                maximum,
+                found,
+            } => write!(
+                formatter,
+                "entry {} has a {} byte payload, the maximum is {}",
+                entry_index, found, maximum
+            ),
+            BlockPayloadTooBig { maximum, found } => write!(
+                formatter,
+                "block has {} payload bytes, the maximum is {}",
+                found, maximum
+            ),
+            EntryNotYetValid { entry_index } => {
+                write!(formatter, "entry {} isn't valid yet", entry_index)
+            }
+            EntryExpired { entry_index } => {
+                write!(formatter, "entry {} has expired", entry_index)
+            }
+            ThresholdKeysNotActive { entry_index } => write!(
+                formatter,
+                "entry {} is owned by threshold keys, which aren't active",
+                entry_index
+            ),
+            CoinfileAlreadySpent {
+                entry_index,
+                coinfile_hash,
+            } => write!(
+                formatter,
+                "entry {} spends coinfile {}, which is already spent",
+                entry_index,
+                hex(coinfile_hash)
+            ),
+            NoPendingEntries => {
+                write!(formatter, "no pending entries to put in a block")
+            }
+            StorageVersionMismatch { expected, found } => write!(
+                formatter,
+                "database has storage version {}, expected {}; delete it and \
+                 sync again",
+                found, expected
+            ),
+            InvalidBlock {
+                kind,
+                entry_index: Some(entry_index),
+            } => write!(
+                formatter,
+                "invalid block: entry {}: {}",
+                entry_index, kind
+            ),
+            InvalidBlock {
+                kind,
+                entry_index: None,
+            } => write!(formatter, "invalid block: {}", kind),
+            Other => write!(formatter, "other error"),
+        }
+    }
+}
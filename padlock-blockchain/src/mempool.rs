@@ -0,0 +1,568 @@
+use bls_signatures::{Serialize, Signature};
+use tracing::{debug, info, warn};
+
+use crate::block::{Block, BlockError, MempoolEntry};
+use crate::signatures::SignedMessages;
+use crate::{Blockchain, BlockchainError};
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::{error::Error, fmt};
+
+/// The default amount of memory, in bytes, pending entries may use.
+pub const DEFAULT_MEMORY_CAP: usize = 64 * 1024 * 1024;
+
+/// The default lowest entry difficulty the mempool will relay.
+pub const DEFAULT_MIN_ENTRY_DIFFICULTY: u64 = 2;
+
+/// How many of the most recently connected blocks to remember the entries of,
+/// so they can be put back into the mempool if those blocks are disconnected.
+const CONFIRMED_BLOCKS_TO_KEEP: usize = 16;
+
+/// Holds entries that are waiting to be put into a block.
+///
+/// Every entry is checked before it is accepted: its signature, that its proof
+/// of work meets the chain's and the mempool's minimum entry difficulty, that
+/// its time locks allow it in the next block, and whether it spends a coinfile
+/// that is already spent in the chain or by another pending entry. Entries
+/// are ranked by their entry difficulty, and once the pending entries use more
+/// memory than the cap, the lowest ranked entries are evicted.
+pub struct Mempool {
+    entries: HashMap<[u8; 64], PendingEntry>,
+    // Pending entries ordered from lowest to highest difficulty
+    ranking: BTreeSet<(u64, [u8; 64])>,
+    // Which pending entry spends each coinfile, under every form of its hash
+    spends: HashMap<[u8; 32], [u8; 64]>,
+    // Entries from recently connected blocks, along with their signatures,
+    // which blocks don't keep once they are aggregated.
+    confirmed: HashMap<[u8; 64], MempoolEntry>,
+    confirmed_blocks: VecDeque<Vec<[u8; 64]>>,
+    memory_cap: usize,
+    memory_used: usize,
+    min_entry_difficulty: u64,
+    generation: u64,
+}
+
+struct PendingEntry {
+    mempool_entry: MempoolEntry,
+    difficulty: u64,
+    size: usize,
+}
+
+impl Mempool {
+    pub fn new(memory_cap: usize, min_entry_difficulty: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ranking: BTreeSet::new(),
+            spends: HashMap::new(),
+            confirmed: HashMap::new(),
+            confirmed_blocks: VecDeque::new(),
+            memory_cap,
+            memory_used: 0,
+            min_entry_difficulty,
+            generation: 0,
+        }
+    }
+
+    /// Checks an entry and adds it to the mempool.
+    ///
+    /// If the mempool is over its memory cap afterwards, the lowest ranked
+    /// entries are evicted. If that includes the new entry, it is rejected with
+    /// `MempoolErrorKind::MempoolFull`.
+    pub fn add(
+        &mut self,
+        mempool_entry: MempoolEntry,
+        blockchain: &Blockchain,
+    ) -> Result<(), MempoolError> {
+        let entry = mempool_entry.entry();
+        let entry_hash = entry.hash()?;
+
+        if self.entries.contains_key(&entry_hash) {
+            return Err(MempoolError::new(MempoolErrorKind::AlreadyInMempool));
+        }
+
+        // An entry below the chain's minimum can't go in any block, so the
+        // mempool's own minimum can only be stricter
+        let minimum = self
+            .min_entry_difficulty
+            .max(blockchain.info.min_entry_difficulty);
+        let difficulty = entry.difficulty()?;
+        if difficulty < minimum {
+            return Err(MempoolError::new(MempoolErrorKind::NotEnoughWork {
+                minimum,
+                found: difficulty,
+            }));
+        }
+
+        // Entries can't be in a block until the next height at the earliest
+        let height = blockchain.info.height + 1;
+        if !blockchain
+            .params
+            .is_entry_version_allowed(entry.version, height)
+        {
+            return Err(MempoolError::new(
+                MempoolErrorKind::EntryVersionNotAllowed {
+                    version: entry.version,
+                },
+            ));
+        }
+
+        if entry.threshold_keys.is_some()
+            && !blockchain.allows_threshold_keys(height)?
+        {
+            return Err(MempoolError::new(
+                MempoolErrorKind::ThresholdKeysNotActive,
+            ));
+        }
+
+        let median_timestamp = blockchain.info.past_median_timestamp;
+        if !entry.is_mature(height, median_timestamp) {
+            return Err(MempoolError::new(MempoolErrorKind::NotYetValid));
+        }
+        if entry.is_expired(height, median_timestamp) {
+            return Err(MempoolError::new(MempoolErrorKind::Expired));
+        }
+
+        let max_payload_size = blockchain.params.max_entry_payload_size;
+        if entry.payload.len() > max_payload_size {
+            return Err(MempoolError::new(MempoolErrorKind::PayloadTooBig {
+                maximum: max_payload_size,
+                found: entry.payload.len(),
+            }));
+        }
+
+        let spend_forms = entry.coinfile_spend_forms();
+        let mut seen_forms = HashSet::new();
+        for coinfile_hash in &spend_forms {
+            // A block can't have the entry if it spends a coinfile twice
+            if !seen_forms.insert(coinfile_hash) {
+                return Err(MempoolError::new(
+                    MempoolErrorKind::DuplicateSpend {
+                        coinfile_hash: *coinfile_hash,
+                    },
+                ));
+            }
+
+            if self.spends.contains_key(coinfile_hash) {
+                return Err(MempoolError::new(
+                    MempoolErrorKind::ConflictsWithPending {
+                        coinfile_hash: *coinfile_hash,
+                    },
+                ));
+            }
+
+            if blockchain.is_coinfile_spent(coinfile_hash)? {
+                return Err(MempoolError::new(
+                    MempoolErrorKind::CoinfileAlreadySpent {
+                        coinfile_hash: *coinfile_hash,
+                    },
+                ));
+            }
+        }
+
+        check_signature(&mempool_entry, blockchain)?;
+
+        let size = entry.to_bytes()?.len() + mempool_entry.signature().len();
+
+        for coinfile_hash in spend_forms {
+            self.spends.insert(coinfile_hash, entry_hash);
+        }
+
+        self.ranking.insert((difficulty, entry_hash));
+        self.memory_used += size;
+        self.generation += 1;
+        self.entries.insert(
+            entry_hash,
+            PendingEntry {
+                mempool_entry,
+                difficulty,
+                size,
+            },
+        );
+
+        debug!(difficulty, size, "added entry to mempool");
+
+        self.evict();
+
+        if !self.entries.contains_key(&entry_hash) {
+            return Err(MempoolError::new(MempoolErrorKind::MempoolFull));
+        }
+
+        Ok(())
+    }
+
+    /// The pending entries, from highest to lowest entry difficulty.
+    pub fn entries(&self) -> Vec<&MempoolEntry> {
+        self.ranking
+            .iter()
+            .rev()
+            .map(|(_, entry_hash)| &self.entries[entry_hash].mempool_entry)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How many bytes the pending entries use.
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    /// Changes whenever an entry is added or removed, so callers can tell
+    /// whether the pending entries changed since they last looked.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Removes the entries a newly connected block contains, along with any
+    /// pending entries that spend the same coinfiles.
+    pub fn block_connected(&mut self, block: &Block) {
+        let mut confirmed_block = Vec::new();
+
+        for entry in &block.entries {
+            let entry_hash = match entry.hash() {
+                Ok(entry_hash) => entry_hash,
+                Err(error) => {
+                    warn!(%error, "couldn't hash connected entry");
+                    continue;
+                }
+            };
+
+            if let Some(pending_entry) = self.remove(&entry_hash) {
+                self.confirmed
+                    .insert(entry_hash, pending_entry.mempool_entry);
+                confirmed_block.push(entry_hash);
+            }
+
+            for coinfile_hash in &entry.coinfile_spend_forms() {
+                if let Some(conflict_hash) = self.spends.get(coinfile_hash) {
+                    let conflict_hash = *conflict_hash;
+                    self.remove(&conflict_hash);
+
+                    debug!("removed entry spending a now spent coinfile");
+                }
+            }
+        }
+
+        self.confirmed_blocks.push_back(confirmed_block);
+        if self.confirmed_blocks.len() > CONFIRMED_BLOCKS_TO_KEEP {
+            for entry_hash in
+                self.confirmed_blocks.pop_front().unwrap_or_default()
+            {
+                self.confirmed.remove(&entry_hash);
+            }
+        }
+    }
+
+    /// Removes the entries of a block that the blockchain rejected with
+    /// `error`: the one entry it blames, or every entry when it blames none.
+    /// A miner would otherwise build the same block from them again.
+    pub fn block_rejected(&mut self, block: &Block, error: &BlockchainError) {
+        let rejected = match error.entry_index() {
+            Some(entry_index) => block.entries.get(entry_index..=entry_index),
+            None => Some(&block.entries[..]),
+        };
+
+        for entry in rejected.unwrap_or_default() {
+            let removed = entry
+                .hash()
+                .ok()
+                .and_then(|entry_hash| self.remove(&entry_hash));
+            if removed.is_some() {
+                debug!("removed entry of a rejected block");
+            }
+        }
+    }
+
+    /// Removes the pending entries that can't be in the next block of
+    /// `blockchain` because their `valid_until` has passed. Should be called
+    /// after each block is connected, as they would otherwise wait forever.
+    pub fn remove_expired(&mut self, blockchain: &Blockchain) {
+        let height = blockchain.info.height + 1;
+        let median_timestamp = blockchain.info.past_median_timestamp;
+
+        let expired: Vec<[u8; 64]> = self
+            .entries
+            .iter()
+            .filter(|(_, pending_entry)| {
+                pending_entry
+                    .mempool_entry
+                    .entry()
+                    .is_expired(height, median_timestamp)
+            })
+            .map(|(entry_hash, _)| *entry_hash)
+            .collect();
+
+        for entry_hash in &expired {
+            self.remove(entry_hash);
+        }
+
+        if !expired.is_empty() {
+            debug!(expired = expired.len(), "removed expired entries");
+        }
+    }
+
+    /// Puts the entries of a disconnected block back into the mempool. Should
+    /// be called after the block has been removed from `blockchain`.
+    ///
+    /// Blocks only keep their aggregated signature, so only entries that went
+    /// through this mempool before being connected can be put back.
+    pub fn block_disconnected(
+        &mut self,
+        block: &Block,
+        blockchain: &Blockchain,
+    ) {
+        let mut restored = 0;
+
+        for entry in &block.entries {
+            let mempool_entry = match entry
+                .hash()
+                .ok()
+                .and_then(|entry_hash| self.confirmed.remove(&entry_hash))
+            {
+                Some(mempool_entry) => mempool_entry,
+                None => {
+                    debug!("no signature for disconnected entry, dropping it");
+                    continue;
+                }
+            };
+
+            match self.add(mempool_entry, blockchain) {
+                Ok(()) => restored += 1,
+                Err(error) => {
+                    debug!(%error, "couldn't put disconnected entry back");
+                }
+            }
+        }
+
+        self.confirmed_blocks.pop_back();
+
+        info!(
+            restored,
+            entries = block.entries.len(),
+            "put disconnected entries back into the mempool"
+        );
+    }
+
+    fn remove(&mut self, entry_hash: &[u8; 64]) -> Option<PendingEntry> {
+        let pending_entry = self.entries.remove(entry_hash)?;
+
+        self.ranking.remove(&(pending_entry.difficulty, *entry_hash));
+        self.memory_used -= pending_entry.size;
+        self.generation += 1;
+
+        let entry = pending_entry.mempool_entry.entry();
+        for coinfile_hash in &entry.coinfile_spend_forms() {
+            self.spends.remove(coinfile_hash);
+        }
+
+        Some(pending_entry)
+    }
+
+    /// Evicts the lowest ranked entries until the mempool is within its cap.
+    fn evict(&mut self) {
+        while self.memory_used > self.memory_cap {
+            let (difficulty, entry_hash) = match self.ranking.iter().next() {
+                Some(lowest) => *lowest,
+                None => break,
+            };
+
+            self.remove(&entry_hash);
+
+            debug!(difficulty, "evicted entry from full mempool");
+        }
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MEMORY_CAP, DEFAULT_MIN_ENTRY_DIFFICULTY)
+    }
+}
+
+fn check_signature(
+    mempool_entry: &MempoolEntry,
+    blockchain: &Blockchain,
+) -> Result<(), MempoolError> {
+    let entry = mempool_entry.entry();
+    let chain_id = blockchain.params.chain_id;
+
+    let mut signer_keys = entry.resolve_signer_keys(&blockchain.db)?;
+    let signature = Signature::from_bytes(mempool_entry.signature())
+        .map_err(|error| {
+            MempoolError::new(MempoolErrorKind::InvalidSignature)
+                .with_source(error)
+        })?;
+
+    // Entries owned by threshold keys are signed by the aggregate of their
+    // signers' signatures
+    if signer_keys.len() != 1 {
+        let messages = signer_keys
+            .iter()
+            .map(|public_key| entry.signing_message(chain_id, public_key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let signed_messages = SignedMessages {
+            signature,
+            public_keys: signer_keys,
+            messages,
+        };
+        if !signed_messages.verify(&blockchain.signature_cache) {
+            return Err(MempoolError::new(MempoolErrorKind::InvalidSignature));
+        }
+
+        return Ok(());
+    }
+
+    let public_key = signer_keys.remove(0);
+    let message = entry.signing_message(chain_id, &public_key)?;
+    if !public_key.verify(signature, &message) {
+        return Err(MempoolError::new(MempoolErrorKind::InvalidSignature));
+    }
+
+    // So the signature isn't checked again when the entry is in a block
+    blockchain.signature_cache.insert(&public_key, &message, signature);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct MempoolError {
+    kind: MempoolErrorKind,
+    source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl MempoolError {
+    fn new(kind: MempoolErrorKind) -> Self {
+        Self { kind, source: None }
+    }
+
+    fn from_source(error: Box<dyn Error + Send + Sync>) -> Self {
+        Self {
+            kind: MempoolErrorKind::Other,
+            source: Some(error),
+        }
+    }
+
+    fn with_source<E>(mut self, error: E) -> Self
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        self.source = Some(Box::new(error));
+        self
+    }
+
+    pub fn kind(&self) -> &MempoolErrorKind {
+        &self.kind
+    }
+}
+
+impl Error for MempoolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn Error + 'static))
+    }
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.kind)?;
+
+        if let Some(source) = &self.source {
+            write!(formatter, ": {}", source)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<BlockError> for MempoolError {
+    fn from(error: BlockError) -> Self {
+        MempoolError::from_source(Box::new(error))
+    }
+}
+
+impl From<BlockchainError> for MempoolError {
+    fn from(error: BlockchainError) -> Self {
+        MempoolError::from_source(Box::new(error))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum MempoolErrorKind {
+    AlreadyInMempool,
+    InvalidSignature,
+    NotEnoughWork { minimum: u64, found: u64 },
+    CoinfileAlreadySpent { coinfile_hash: [u8; 32] },
+    ConflictsWithPending { coinfile_hash: [u8; 32] },
+    /// The entry spends the same coinfile more than once.
+    DuplicateSpend { coinfile_hash: [u8; 32] },
+    /// Entries of the version can't be put in the next block.
+    EntryVersionNotAllowed { version: u8 },
+    /// The entry's payload is longer than the network allows.
+    PayloadTooBig { maximum: usize, found: usize },
+    /// The entry's `valid_after` won't have passed at the next block.
+    NotYetValid,
+    /// The entry's `valid_until` will have passed at the next block.
+    Expired,
+    /// The entry is owned by threshold keys, which aren't active at the next
+    /// block.
+    ThresholdKeysNotActive,
+    MempoolFull,
+    Other,
+}
+
+impl fmt::Display for MempoolErrorKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        use MempoolErrorKind::*;
+
+        match self {
+            AlreadyInMempool => write!(formatter, "entry already in mempool"),
+            InvalidSignature => write!(formatter, "invalid entry signature"),
+            NotEnoughWork { minimum, found } => write!(
+                formatter,
+                "entry difficulty {} is below the minimum {}",
+                found, minimum
+            ),
+            CoinfileAlreadySpent { coinfile_hash } => write!(
+                formatter,
+                "coinfile {} is already spent",
+                crate::hex(coinfile_hash)
+            ),
+            ConflictsWithPending { coinfile_hash } => write!(
+                formatter,
+                "coinfile {} is spent by another pending entry",
+                crate::hex(coinfile_hash)
+            ),
+            DuplicateSpend { coinfile_hash } => write!(
+                formatter,
+                "coinfile {} is spent more than once by the entry",
+                crate::hex(coinfile_hash)
+            ),
+            EntryVersionNotAllowed { version } => write!(
+                formatter,
+                "version {} entries aren't valid in the next block",
+                version
+            ),
+            PayloadTooBig { maximum, found } => write!(
+                formatter,
+                "payload is {} bytes, the maximum is {}",
+                found, maximum
+            ),
+            NotYetValid => write!(formatter, "entry isn't valid yet"),
+            Expired => write!(formatter, "entry has expired"),
+            ThresholdKeysNotActive => {
+                write!(formatter, "threshold keys aren't active yet")
+            }
+            MempoolFull => {
+                write!(formatter, "entry difficulty too low for a full mempool")
+            }
+            Other => write!(formatter, "other error"),
+        }
+    }
+}
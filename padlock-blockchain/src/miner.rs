@@ -0,0 +1,361 @@
+use crate::randomx_bindings::{
+    RandomxDataset, RandomxError, RandomxFlags, RandomxVm,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::block::BlockError;
+use crate::mempool::Mempool;
+use crate::template::BlockTemplate;
+use crate::{Blockchain, BlockchainError, BlockchainErrorKind};
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How often the miner checks whether the tip or the mempool changed, and how
+/// long it waits before trying again when there are no entries to mine.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// How many hashes each thread does between checking whether it should stop
+const HASHES_PER_CHECK: u64 = 256;
+
+/// Mines blocks on top of a shared blockchain, using the entries of a shared
+/// mempool.
+///
+/// The nonce space is split evenly between the threads, and the RandomX
+/// dataset is kept for as long as the seed key stays the same. Whenever the
+/// tip of the chain or the pending entries change, the template is rebuilt. So
+/// is a template whose nonces all missed, once its timestamp can move on.
+pub struct Miner {
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    miner_address: [u8; 32],
+    threads: usize,
+    // The dataset for the key the miner last hashed with
+    dataset: Option<([u8; 32], RandomxDataset)>,
+}
+
+// A RandomX input and the hash it gives
+type Solution = (Vec<u8>, [u8; 32]);
+
+/// A template being mined, and how far each thread got through its share of
+/// the nonces.
+struct Work {
+    template: BlockTemplate,
+    mempool_generation: u64,
+    next_nonces: Vec<u64>,
+}
+
+impl Miner {
+    pub fn new(
+        blockchain: Arc<Mutex<Blockchain>>,
+        mempool: Arc<Mutex<Mempool>>,
+        miner_address: [u8; 32],
+        threads: usize,
+    ) -> Self {
+        Self {
+            blockchain,
+            mempool,
+            miner_address,
+            threads: threads.max(1),
+            dataset: None,
+        }
+    }
+
+    /// Mines blocks until `stop` is set.
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<(), BlockchainError> {
+        while self.mine_block(stop)?.is_some() {}
+
+        Ok(())
+    }
+
+    /// Mines until a block is accepted by the blockchain, returning its hash,
+    /// or until `stop` is set, returning `None`.
+    pub fn mine_block(
+        &mut self,
+        stop: &AtomicBool,
+    ) -> Result<Option<[u8; 32]>, BlockchainError> {
+        let mut work: Option<Work> = None;
+
+        while !stop.load(Ordering::Relaxed) {
+            let mut current = match self.update_work(work.take())? {
+                Some(current) => current,
+                None => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+
+            let (randomx_input, hash) = match self.search(&mut current, stop)? {
+                Some(solution) => solution,
+                None => {
+                    // Nothing is left to hash until the tip, the entries or
+                    // the time change
+                    if current.is_exhausted() {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+
+                    work = Some(current);
+                    continue;
+                }
+            };
+
+            let block = current.template.into_block(randomx_input, hash);
+            info!(height = block.header.height, "mined block");
+
+            let mut blockchain =
+                self.blockchain.lock().expect("blockchain lock poisoned");
+            match blockchain.add_block(block.clone()) {
+                Ok(()) => {
+                    let mut mempool =
+                        self.mempool.lock().expect("mempool lock poisoned");
+                    mempool.block_connected(&block);
+                    mempool.remove_expired(&blockchain);
+
+                    return Ok(Some(hash));
+                }
+                Err(error) => {
+                    warn!(%error, "mined block was rejected");
+
+                    // Unless another block took the tip first, the entries
+                    // are to blame, and mining them again would fail the same
+                    // way
+                    let previous_hash = &block.header.previous_hash;
+                    if *previous_hash == blockchain.info.top_block_hash {
+                        self.mempool
+                            .lock()
+                            .expect("mempool lock poisoned")
+                            .block_rejected(&block, &error);
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Builds a new template if the tip or the mempool changed since `work`
+    /// was made, or its nonces ran out and a later timestamp can be used.
+    /// Returns `None` when there are no entries to mine.
+    fn update_work(
+        &self,
+        work: Option<Work>,
+    ) -> Result<Option<Work>, BlockchainError> {
+        let blockchain =
+            self.blockchain.lock().expect("blockchain lock poisoned");
+        let mempool = self.mempool.lock().expect("mempool lock poisoned");
+
+        if let Some(work) = work {
+            if !is_stale(&work, &blockchain, &mempool) {
+                return Ok(Some(work));
+            }
+
+            debug!("work is stale, rebuilding template");
+        }
+
+        let template = match BlockTemplate::new(
+            &blockchain,
+            &mempool,
+            self.miner_address,
+        ) {
+            Ok(template) => template,
+            Err(error)
+                if error.kind() == &BlockchainErrorKind::NoPendingEntries =>
+            {
+                return Ok(None);
+            }
+            Err(error) => return Err(error),
+        };
+
+        let nonces_per_thread = u64::MAX / self.threads as u64;
+        let next_nonces = (0..self.threads as u64)
+            .map(|thread_index| thread_index * nonces_per_thread)
+            .collect();
+
+        Ok(Some(Work {
+            template,
+            mempool_generation: mempool.generation(),
+            next_nonces,
+        }))
+    }
+
+    /// Hashes nonces on every thread until one meets the template's target,
+    /// the work goes stale or `stop` is set.
+    fn search(
+        &mut self,
+        work: &mut Work,
+        stop: &AtomicBool,
+    ) -> Result<Option<Solution>, BlockchainError> {
+        let blockchain = &self.blockchain;
+        let mempool = &self.mempool;
+        let dataset = cached_dataset(
+            &mut self.dataset,
+            work.template.seed_key(),
+            self.threads,
+        )?;
+
+        // The header part of the hashed input stays the same for every nonce
+        let header_input = work.template.header().pow_input(&[])?;
+
+        let done = AtomicBool::new(false);
+        let solution = Mutex::new(None);
+        let next_nonces: Vec<AtomicU64> =
+            work.next_nonces.iter().copied().map(AtomicU64::new).collect();
+        let nonces_per_thread = u64::MAX / self.threads as u64;
+
+        let template = &work.template;
+        let header_input = &header_input[..];
+        let done_ref = &done;
+        let solution_ref = &solution;
+
+        let result = thread::scope(|scope| {
+            let workers: Vec<_> = next_nonces
+                .iter()
+                .enumerate()
+                .map(|(thread_index, next_nonce)| {
+                    let end = (thread_index as u64 + 1) * nonces_per_thread;
+
+                    scope.spawn(move || {
+                        hash_nonces(
+                            dataset,
+                            template,
+                            header_input,
+                            next_nonce,
+                            end,
+                            done_ref,
+                            solution_ref,
+                        )
+                    })
+                })
+                .collect();
+
+            while !done.load(Ordering::Relaxed)
+                && !workers.iter().all(|worker| worker.is_finished())
+            {
+                thread::sleep(POLL_INTERVAL);
+
+                let is_stale = is_stale(
+                    work,
+                    &blockchain.lock().expect("blockchain lock poisoned"),
+                    &mempool.lock().expect("mempool lock poisoned"),
+                );
+                if stop.load(Ordering::Relaxed) || is_stale {
+                    done.store(true, Ordering::Relaxed);
+                }
+            }
+
+            done.store(true, Ordering::Relaxed);
+            workers.into_iter().try_for_each(|worker| {
+                worker.join().expect("miner thread panicked")
+            })
+        });
+        result.map_err(BlockError::from)?;
+
+        work.next_nonces = next_nonces
+            .iter()
+            .map(|next_nonce| next_nonce.load(Ordering::Relaxed))
+            .collect();
+
+        Ok(solution.into_inner().expect("solution lock poisoned"))
+    }
+}
+
+/// The dataset for `key`, which is only rebuilt when the key changes.
+fn cached_dataset<'a>(
+    cache: &'a mut Option<([u8; 32], RandomxDataset)>,
+    key: &[u8; 32],
+    threads: usize,
+) -> Result<&'a RandomxDataset, BlockchainError> {
+    let is_current = matches!(
+        cache,
+        Some((dataset_key, _)) if dataset_key == key
+    );
+
+    if !is_current {
+        debug!("building randomx dataset");
+
+        let flags = RandomxFlags::default() | RandomxFlags::FULLMEM;
+        let threads = threads.min(u8::MAX as usize) as u8;
+        let dataset = RandomxDataset::new(flags, key, threads)
+            .map_err(BlockError::from)?;
+        *cache = Some((*key, dataset));
+    }
+
+    match cache {
+        Some((_, dataset)) => Ok(dataset),
+        None => unreachable!("dataset was just built"),
+    }
+}
+
+impl Work {
+    /// Whether every thread went through all of its share of the nonces.
+    fn is_exhausted(&self) -> bool {
+        let threads = self.next_nonces.len() as u64;
+        let nonces_per_thread = u64::MAX / threads;
+
+        self.next_nonces
+            .iter()
+            .enumerate()
+            .all(|(thread_index, next_nonce)| {
+                *next_nonce >= (thread_index as u64 + 1) * nonces_per_thread
+            })
+    }
+}
+
+fn is_stale(work: &Work, blockchain: &Blockchain, mempool: &Mempool) -> bool {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    work.template.header().previous_hash != blockchain.info.top_block_hash
+        || work.mempool_generation != mempool.generation()
+        || (work.is_exhausted() && work.template.header().timestamp < now)
+}
+
+/// Hashes nonces from `next_nonce` up to `end` with one thread, stopping once
+/// `done` is set. The RandomX input is the nonce's little endian bytes.
+fn hash_nonces(
+    dataset: &RandomxDataset,
+    template: &BlockTemplate,
+    header_input: &[u8],
+    next_nonce: &AtomicU64,
+    end: u64,
+    done: &AtomicBool,
+    solution: &Mutex<Option<Solution>>,
+) -> Result<(), RandomxError> {
+    let flags = RandomxFlags::default() | RandomxFlags::FULLMEM;
+    let vm = RandomxVm::new_fast(flags, dataset)?;
+
+    let mut pow_input = header_input.to_vec();
+    let header_len = pow_input.len();
+
+    let mut nonce = next_nonce.load(Ordering::Relaxed);
+    while nonce < end && !done.load(Ordering::Relaxed) {
+        let batch_end = end.min(nonce.saturating_add(HASHES_PER_CHECK));
+
+        for batch_nonce in nonce..batch_end {
+            let randomx_input = batch_nonce.to_le_bytes().to_vec();
+
+            pow_input.truncate(header_len);
+            pow_input.extend_from_slice(&randomx_input);
+            let hash = vm.hash(&pow_input);
+
+            if template.meets_target(&hash) {
+                *solution.lock().expect("solution lock poisoned") =
+                    Some((randomx_input, hash));
+                done.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+
+        nonce = batch_end;
+        next_nonce.store(nonce, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
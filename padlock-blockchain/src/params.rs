@@ -0,0 +1,106 @@
+use crate::block::{
+    ENTRY_VERSION_1, ENTRY_VERSION_2, ENTRY_VERSION_3, ENTRY_VERSION_4,
+    ENTRY_VERSION_5,
+};
+use crate::deployment::{Deployment, DeploymentId};
+use crate::difficulty::{DifficultyAlgorithm, SimpleAverage};
+use crate::reward::SubsidySchedule;
+
+use std::sync::Arc;
+
+/// The chain id of the main network.
+pub const MAIN_CHAIN_ID: u32 = 1;
+
+/// The rules that can differ between networks, such as the main network and
+/// a test network.
+#[derive(Clone)]
+pub struct NetworkParams {
+    /// Identifies the network in every entry signature, so an entry signed
+    /// for one network isn't valid on another. Every network, including
+    /// forks, needs its own.
+    pub chain_id: u32,
+    /// The target interval between blocks, in seconds.
+    pub block_time: u64,
+    /// How the target is adjusted after each block.
+    pub difficulty_algorithm: Arc<dyn DifficultyAlgorithm>,
+    /// How much each block pays its miner.
+    pub subsidy: SubsidySchedule,
+    /// How many blocks deployment signalling is counted over. Deployment
+    /// states only change at multiples of this height.
+    pub signalling_window: u64,
+    /// How many blocks in a window must signal for a deployment to lock in.
+    pub activation_threshold: u64,
+    pub deployments: Vec<Deployment>,
+    /// The first height whose blocks can have version 2 entries.
+    pub entry_v2_height: u64,
+    /// The first height whose blocks can no longer have version 1 entries.
+    /// Blocks in between can have either.
+    pub entry_v1_end_height: u64,
+    /// The first height whose blocks can have version 3 entries, which carry
+    /// payloads.
+    pub entry_v3_height: u64,
+    /// The first height whose blocks can have version 4 entries, which carry
+    /// time locks.
+    pub entry_v4_height: u64,
+    /// The first height whose blocks can have version 5 entries, which can be
+    /// owned by threshold keys.
+    pub entry_v5_height: u64,
+    /// The longest payload an entry can have, in bytes.
+    pub max_entry_payload_size: usize,
+    /// The most payload bytes all of a block's entries can have together.
+    pub max_block_payload_size: usize,
+}
+
+impl NetworkParams {
+    pub fn deployment(&self, id: DeploymentId) -> Option<&Deployment> {
+        self.deployments
+            .iter()
+            .find(|deployment| deployment.id == id)
+    }
+
+    /// Whether a block at `height` can have entries of `version`.
+    pub fn is_entry_version_allowed(&self, version: u8, height: u64) -> bool {
+        match version {
+            ENTRY_VERSION_1 => height < self.entry_v1_end_height,
+            ENTRY_VERSION_2 => height >= self.entry_v2_height,
+            ENTRY_VERSION_3 => height >= self.entry_v3_height,
+            ENTRY_VERSION_4 => height >= self.entry_v4_height,
+            ENTRY_VERSION_5 => height >= self.entry_v5_height,
+            _ => false,
+        }
+    }
+}
+
+impl Default for NetworkParams {
+    fn default() -> Self {
+        Self {
+            chain_id: MAIN_CHAIN_ID,
+            block_time: 90,
+            difficulty_algorithm: Arc::new(SimpleAverage::default()),
+            subsidy: SubsidySchedule::default(),
+            signalling_window: 2016,
+            activation_threshold: 1916, // 95%
+            deployments: vec![
+                // Never starts, test networks can give it heights to try out
+                // activation
+                Deployment::new(
+                    DeploymentId::TestDummy,
+                    28,
+                    u64::MAX,
+                    u64::MAX,
+                ),
+                // Signalled for from the start, for about a year
+                Deployment::new(DeploymentId::ThresholdKeys, 0, 0, 350_400),
+            ],
+            // Version 1 entries stay valid for about 104 days at 90 second
+            // blocks, which gives wallets time to move to version 2
+            entry_v2_height: 0,
+            entry_v1_end_height: 100_000,
+            entry_v3_height: 0,
+            entry_v4_height: 0,
+            entry_v5_height: 0,
+            max_entry_payload_size: 256,
+            max_block_payload_size: 16384,
+        }
+    }
+}
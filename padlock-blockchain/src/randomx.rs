@@ -0,0 +1,95 @@
+use crate::randomx_bindings::{
+    RandomxCache, RandomxError, RandomxFlags, RandomxVm,
+};
+
+use tracing::trace;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// How many consecutive blocks are hashed with the same RandomX key.
+pub const SEED_EPOCH_LENGTH: u64 = 2048;
+
+/// How many blocks after the start of an epoch its key comes into use. Gives
+/// miners and verifiers time to initialise the new key before they need it,
+/// and keeps short reorganisations from changing the key.
+pub const SEED_EPOCH_LAG: u64 = 64;
+
+/// The RandomX key until the first seed block is far enough behind.
+pub const GENESIS_SEED_KEY: [u8; 32] = [0u8; 32];
+
+/// How many RandomX caches are kept around at once. The key only changes once
+/// per seed epoch, so only a few are ever in use at the same time.
+pub const MAX_CACHED_KEYS: usize = 4;
+
+/// The height of the block whose hash is the RandomX key for a block at
+/// `height`. Height 0 means the key is `GENESIS_SEED_KEY`.
+pub fn seed_height(height: u64) -> u64 {
+    if height <= SEED_EPOCH_LAG {
+        return 0;
+    }
+
+    (height - SEED_EPOCH_LAG - 1) / SEED_EPOCH_LENGTH * SEED_EPOCH_LENGTH
+}
+
+// Caches from least to most recently used, along with their keys
+type Caches = VecDeque<(Vec<u8>, Arc<RandomxCache>)>;
+
+static CACHES: OnceLock<Mutex<Caches>> = OnceLock::new();
+
+/// Hashes `input` with RandomX keyed by `key`, in light mode.
+///
+/// Initialising a cache is the slow part of hashing, so the caches of the
+/// most recently used keys are shared by every thread. VMs can't be shared
+/// between threads, so a new one is made for each hash.
+pub fn hash(key: &[u8], input: &[u8]) -> Result<[u8; 32], RandomxError> {
+    let flags = RandomxFlags::default();
+
+    let cache = cache(flags, key)?;
+    let vm = RandomxVm::new(flags, &cache)?;
+
+    Ok(vm.hash(input))
+}
+
+/// Gets the cache for `key`, initialising it if it isn't cached. The lock
+/// isn't held while initialising, so other keys can still be used.
+fn cache(
+    flags: RandomxFlags,
+    key: &[u8],
+) -> Result<Arc<RandomxCache>, RandomxError> {
+    let caches = CACHES.get_or_init(|| Mutex::new(VecDeque::new()));
+
+    if let Some(cache) = find(&mut caches.lock().expect("poisoned"), key) {
+        return Ok(cache);
+    }
+
+    trace!("initialising randomx cache");
+    let cache = Arc::new(RandomxCache::new(flags, key)?);
+
+    let mut caches = caches.lock().expect("poisoned");
+
+    // Another thread may have initialised the same key in the meantime
+    if let Some(cache) = find(&mut caches, key) {
+        return Ok(cache);
+    }
+
+    caches.push_back((key.to_vec(), Arc::clone(&cache)));
+    if caches.len() > MAX_CACHED_KEYS {
+        caches.pop_front();
+    }
+
+    Ok(cache)
+}
+
+/// Finds the cache for `key`, marking it as the most recently used.
+fn find(caches: &mut Caches, key: &[u8]) -> Option<Arc<RandomxCache>> {
+    let index = caches
+        .iter()
+        .position(|(cached_key, _)| cached_key.as_slice() == key)?;
+
+    let entry = caches.remove(index)?;
+    let cache = Arc::clone(&entry.1);
+    caches.push_back(entry);
+
+    Some(cache)
+}
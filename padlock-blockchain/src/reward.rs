@@ -0,0 +1,117 @@
+use crate::encoding::{Decoder, Encoder, EncodingError};
+
+/// How many of the smallest units make up one coin.
+pub const COIN: u64 = 100_000_000;
+
+/// About four years of blocks, at one block every 90 seconds.
+pub const DEFAULT_HALVING_INTERVAL: u64 = 1_401_600;
+
+/// How much new money each block pays to its miner's address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubsidySchedule {
+    /// The reward of the first block, in the smallest units.
+    pub initial_reward: u64,
+    /// How many blocks there are between halvings of the reward. Zero means
+    /// the reward never halves.
+    pub halving_interval: u64,
+    /// The least a block is rewarded, however many times the reward halved.
+    pub tail_emission: u64,
+}
+
+impl SubsidySchedule {
+    pub fn new(
+        initial_reward: u64,
+        halving_interval: u64,
+        tail_emission: u64,
+    ) -> Self {
+        Self {
+            initial_reward,
+            halving_interval,
+            tail_emission,
+        }
+    }
+
+    /// The reward of the block at `height`. The first block is at height 1.
+    pub fn reward(&self, height: u64) -> u64 {
+        let halvings = match self.halving_interval {
+            0 => 0,
+            interval => height.saturating_sub(1) / interval,
+        };
+
+        let reward = if halvings < 64 {
+            self.initial_reward >> halvings
+        } else {
+            0
+        };
+
+        reward.max(self.tail_emission)
+    }
+}
+
+impl Default for SubsidySchedule {
+    fn default() -> Self {
+        Self::new(50 * COIN, DEFAULT_HALVING_INTERVAL, COIN / 2)
+    }
+}
+
+/// A reward paid to a miner address by a block in the chain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reward {
+    pub height: u64,
+    pub block_hash: [u8; 32],
+    pub amount: u64,
+}
+
+impl Reward {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.put_u64(self.height);
+        encoder.put_array(&self.block_hash);
+        encoder.put_u64(self.amount);
+
+        encoder.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let mut decoder = Decoder::new(bytes);
+
+        let reward = Reward {
+            height: decoder.get_u64()?,
+            block_hash: decoder.get_array()?,
+            amount: decoder.get_u64()?,
+        };
+        decoder.finish()?;
+
+        Ok(reward)
+    }
+}
+
+/// What the chain knows about a miner address: its balance, and how many
+/// rewards it has been paid, which are stored one per key.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct RewardAccount {
+    pub balance: u64,
+    pub reward_count: u64,
+}
+
+impl RewardAccount {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.put_u64(self.balance);
+        encoder.put_u64(self.reward_count);
+
+        encoder.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let mut decoder = Decoder::new(bytes);
+
+        let account = RewardAccount {
+            balance: decoder.get_u64()?,
+            reward_count: decoder.get_u64()?,
+        };
+        decoder.finish()?;
+
+        Ok(account)
+    }
+}
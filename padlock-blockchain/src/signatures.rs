@@ -0,0 +1,238 @@
+use bls_signatures::{PublicKey, Serialize, Signature};
+
+use blake2::{Blake2b, Digest};
+
+use ff::{Field, PrimeField};
+use groupy::{CurveAffine, CurveProjective};
+use paired::bls12_381::{Bls12, Fq12, Fr, FrRepr, G1Affine, G2};
+use paired::{Engine, PairingCurveAffine};
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+/// How many verified signatures `SignatureCache` keeps by default.
+pub const DEFAULT_SIGNATURE_CACHE_SIZE: usize = 65536;
+
+/// An aggregate signature, along with every public key and the message it
+/// signs.
+pub struct SignedMessages {
+    pub signature: Signature,
+    pub public_keys: Vec<PublicKey>,
+    pub messages: Vec<Vec<u8>>,
+}
+
+impl SignedMessages {
+    /// Checks the aggregate signature. If every key and message has a
+    /// verified signature in `cache`, those are aggregated and compared with
+    /// it instead, which skips the pairing.
+    ///
+    /// That is as good as the pairing because a valid signature over a set of
+    /// keys and messages is unique: it can only be the aggregate of their own
+    /// signatures.
+    pub fn verify(&self, cache: &SignatureCache) -> bool {
+        let cached_signatures: Option<Vec<Signature>> = self
+            .public_keys
+            .iter()
+            .zip(&self.messages)
+            .map(|(public_key, message)| cache.get(public_key, message))
+            .collect();
+
+        if let Some(cached_signatures) = cached_signatures {
+            if let Ok(aggregate) = bls_signatures::aggregate(&cached_signatures)
+            {
+                return aggregate.as_bytes() == self.signature.as_bytes();
+            }
+        }
+
+        let messages: Vec<&[u8]> =
+            self.messages.iter().map(|message| &message[..]).collect();
+        bls_signatures::verify_messages(
+            &self.signature,
+            &messages,
+            &self.public_keys,
+        )
+    }
+}
+
+/// Signatures that have already been verified, keyed by their public key and
+/// message. The mempool fills it as it admits entries, so an entry's signature
+/// isn't checked with a pairing again when it arrives in a block.
+///
+/// Only verified signatures may be inserted. The oldest are evicted first once
+/// the cache is full.
+pub struct SignatureCache {
+    capacity: usize,
+    signatures: Mutex<CachedSignatures>,
+}
+
+#[derive(Default)]
+struct CachedSignatures {
+    by_key: HashMap<[u8; 64], Signature>,
+    // Keys from oldest to newest
+    order: VecDeque<[u8; 64]>,
+}
+
+impl SignatureCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            signatures: Mutex::new(CachedSignatures::default()),
+        }
+    }
+
+    /// Remembers that `signature` is a valid signature of `message` by
+    /// `public_key`.
+    pub fn insert(
+        &self,
+        public_key: &PublicKey,
+        message: &[u8],
+        signature: Signature,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = cache_key(public_key, message);
+        let mut signatures = self.signatures.lock().expect("poisoned");
+
+        if signatures.by_key.insert(key, signature).is_none() {
+            signatures.order.push_back(key);
+        }
+
+        while signatures.order.len() > self.capacity {
+            if let Some(oldest) = signatures.order.pop_front() {
+                signatures.by_key.remove(&oldest);
+            }
+        }
+    }
+
+    /// The verified signature of `message` by `public_key`, if there is one.
+    pub fn get(
+        &self,
+        public_key: &PublicKey,
+        message: &[u8],
+    ) -> Option<Signature> {
+        let key = cache_key(public_key, message);
+        let signatures = self.signatures.lock().expect("poisoned");
+
+        signatures.by_key.get(&key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.lock().expect("poisoned").order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SIGNATURE_CACHE_SIZE)
+    }
+}
+
+fn cache_key(public_key: &PublicKey, message: &[u8]) -> [u8; 64] {
+    let mut hasher = Blake2b::new();
+    hasher.update(public_key.as_bytes());
+    hasher.update(message);
+
+    hasher.finalize()[..].try_into().unwrap()
+}
+
+/// Checks many aggregate signatures with one pairing check, such as those of
+/// the blocks in a batch being synced. Returns whether every one of them is
+/// valid, but not which ones aren't.
+///
+/// Adding the signatures up and checking the sum against every key and
+/// message would let one invalid signature make up for another, so each is
+/// weighted by a scalar first, along with its messages. The scalars come from
+/// a hash of the whole batch, so they can't be known until every signature in
+/// it has been picked.
+pub fn verify_batch(batch: &[&SignedMessages]) -> bool {
+    let seed = batch_seed(batch);
+
+    let mut weighted_signature = G2::zero();
+    let mut pairs = Vec::new();
+
+    for (index, signed_messages) in batch.iter().enumerate() {
+        // Each would fail on its own, so the batch has to as well
+        if signed_messages.messages.is_empty()
+            || signed_messages.messages.len()
+                != signed_messages.public_keys.len()
+        {
+            return false;
+        }
+
+        let weight = batch_weight(&seed, index);
+
+        let mut signature = G2::from(signed_messages.signature);
+        signature.mul_assign(weight);
+        weighted_signature.add_assign(&signature);
+
+        for (public_key, message) in signed_messages
+            .public_keys
+            .iter()
+            .zip(&signed_messages.messages)
+        {
+            let public_key = public_key.as_affine();
+            if public_key.is_zero() {
+                return false;
+            }
+
+            let mut hash = bls_signatures::hash(message);
+            hash.mul_assign(weight);
+            pairs.push((public_key.prepare(), hash.into_affine().prepare()));
+        }
+    }
+
+    if pairs.is_empty() {
+        return true;
+    }
+
+    // e(g1, sum of r_i * signature_i) == product of e(pk_ij, r_i * H(m_ij))
+    let mut generator = G1Affine::one();
+    generator.negate();
+    pairs.push((
+        generator.prepare(),
+        weighted_signature.into_affine().prepare(),
+    ));
+
+    let pairs: Vec<_> = pairs.iter().map(|(p, q)| (p, q)).collect();
+    match Bls12::final_exponentiation(&Bls12::miller_loop(&pairs)) {
+        Some(result) => result == Fq12::one(),
+        None => false,
+    }
+}
+
+/// A hash committing to every signature, key and message in the batch.
+fn batch_seed(batch: &[&SignedMessages]) -> [u8; 64] {
+    let mut hasher = Blake2b::new();
+    for signed_messages in batch {
+        hasher.update(signed_messages.signature.as_bytes());
+
+        for (public_key, message) in signed_messages
+            .public_keys
+            .iter()
+            .zip(&signed_messages.messages)
+        {
+            hasher.update(public_key.as_bytes());
+            hasher.update((message.len() as u64).to_le_bytes());
+            hasher.update(message);
+        }
+    }
+
+    hasher.finalize()[..].try_into().unwrap()
+}
+
+/// The non zero scalar the signature at `index` in a batch is weighted by.
+fn batch_weight(seed: &[u8; 64], index: usize) -> Fr {
+    let mut hasher = Blake2b::new();
+    hasher.update(seed);
+    hasher.update((index as u64).to_le_bytes());
+
+    let weight = u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap());
+    Fr::from_repr(FrRepr::from(weight.max(1))).expect("u64 is below modulus")
+}
@@ -0,0 +1,327 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Div, Shr};
+
+/// A 256 bit unsigned integer, used for proof of work targets. Hashes are
+/// read as big endian integers, so a hash with more leading zeros is lower.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct U256 {
+    // Least significant limb first
+    limbs: [u64; 4],
+}
+
+impl U256 {
+    pub const ZERO: U256 = U256 { limbs: [0; 4] };
+    pub const ONE: U256 = U256 {
+        limbs: [1, 0, 0, 0],
+    };
+    pub const MAX: U256 = U256 {
+        limbs: [u64::MAX; 4],
+    };
+
+    pub fn from_u64(value: u64) -> Self {
+        U256 {
+            limbs: [value, 0, 0, 0],
+        }
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (index, chunk) in bytes.chunks_exact(8).enumerate() {
+            let mut limb = [0u8; 8];
+            limb.copy_from_slice(chunk);
+            limbs[3 - index] = u64::from_be_bytes(limb);
+        }
+
+        U256 { limbs }
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (index, chunk) in bytes.chunks_exact_mut(8).enumerate() {
+            chunk.copy_from_slice(&self.limbs[3 - index].to_be_bytes());
+        }
+
+        bytes
+    }
+
+    /// How many bits are needed to hold the value.
+    pub fn bits(self) -> u32 {
+        for index in (0..4).rev() {
+            if self.limbs[index] != 0 {
+                return index as u32 * 64 + 64
+                    - self.limbs[index].leading_zeros();
+            }
+        }
+
+        0
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for (index, limb) in limbs.iter_mut().enumerate() {
+            let (sum, overflow) =
+                self.limbs[index].overflowing_add(other.limbs[index]);
+            let (sum, carry_overflow) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = overflow || carry_overflow;
+        }
+
+        if carry {
+            return None;
+        }
+
+        Some(U256 { limbs })
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for (index, limb) in limbs.iter_mut().enumerate() {
+            let (difference, overflow) =
+                self.limbs[index].overflowing_sub(other.limbs[index]);
+            let (difference, borrow_overflow) =
+                difference.overflowing_sub(borrow as u64);
+            *limb = difference;
+            borrow = overflow || borrow_overflow;
+        }
+
+        if borrow {
+            return None;
+        }
+
+        Some(U256 { limbs })
+    }
+
+    /// Shifts left by `shift` bits, returning `None` if any set bit would be
+    /// shifted out.
+    pub fn checked_shl(self, shift: u32) -> Option<Self> {
+        if self == U256::ZERO {
+            return Some(self);
+        }
+        if self.bits() + shift > 256 {
+            return None;
+        }
+
+        let limb_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+
+        let mut limbs = [0u64; 4];
+        for index in (limb_shift..4).rev() {
+            let source = index - limb_shift;
+            limbs[index] = self.limbs[source] << bit_shift;
+            if bit_shift > 0 && source > 0 {
+                limbs[index] |= self.limbs[source - 1] >> (64 - bit_shift);
+            }
+        }
+
+        Some(U256 { limbs })
+    }
+
+    /// Multiplies by `multiplier` then divides by `divisor`, without losing
+    /// precision in between. Saturates at `U256::MAX`.
+    pub fn mul_div(self, multiplier: u64, divisor: u64) -> Self {
+        assert!(divisor != 0, "division by zero");
+
+        // The product needs a fifth limb
+        let mut product = [0u64; 5];
+        let mut carry = 0u128;
+        for (index, limb) in self.limbs.iter().enumerate() {
+            let limb = *limb as u128 * multiplier as u128 + carry;
+            product[index] = limb as u64;
+            carry = limb >> 64;
+        }
+        product[4] = carry as u64;
+
+        let mut quotient = [0u64; 5];
+        let mut remainder = 0u128;
+        for index in (0..5).rev() {
+            let dividend = (remainder << 64) | product[index] as u128;
+            quotient[index] = (dividend / divisor as u128) as u64;
+            remainder = dividend % divisor as u128;
+        }
+
+        if quotient[4] != 0 {
+            return U256::MAX;
+        }
+
+        U256 {
+            limbs: [quotient[0], quotient[1], quotient[2], quotient[3]],
+        }
+    }
+
+    /// The nearest float, for difficulty calculations.
+    pub fn to_f64(self) -> f64 {
+        self.limbs
+            .iter()
+            .rev()
+            .fold(0f64, |total, limb| total * 2f64.powi(64) + *limb as f64)
+    }
+
+    /// Converts a non negative float, truncating the fraction. Saturates at
+    /// `U256::MAX`.
+    pub fn from_f64(value: f64) -> Self {
+        if value.is_nan() || value < 1f64 {
+            return U256::ZERO;
+        }
+        if value >= 2f64.powi(256) {
+            return U256::MAX;
+        }
+
+        // Floats hold 53 bits of precision, so take the top bits as an
+        // integer and shift them into place
+        let exponent = value.log2().floor() as i32;
+        let shift = (exponent - 62).max(0);
+        let mantissa = (value / 2f64.powi(shift)) as u64;
+
+        U256::from_u64(mantissa)
+            .checked_shl(shift as u32)
+            .unwrap_or(U256::MAX)
+    }
+
+    /// Decodes a target from its compact form: the high byte is the length of
+    /// the target in bytes, and the low three bytes are its most significant
+    /// bytes. The sign bit (0x00800000) is never set by `to_compact`, and is
+    /// ignored. Targets too big for 256 bits saturate.
+    pub fn from_compact(compact: u32) -> Self {
+        let size = compact >> 24;
+        let mantissa = U256::from_u64((compact & 0x007f_ffff) as u64);
+
+        if size <= 3 {
+            mantissa >> (8 * (3 - size))
+        } else {
+            mantissa.checked_shl(8 * (size - 3)).unwrap_or(U256::MAX)
+        }
+    }
+
+    /// Encodes the target in compact form, rounding down to the three most
+    /// significant bytes.
+    pub fn to_compact(self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut mantissa = if size <= 3 {
+            (self.limbs[0] << (8 * (3 - size))) as u32
+        } else {
+            (self >> (8 * (size - 3))).limbs[0] as u32
+        };
+
+        // Keep the sign bit clear
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        (size << 24) | mantissa
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = Self;
+
+    fn shr(self, shift: u32) -> Self {
+        if shift >= 256 {
+            return U256::ZERO;
+        }
+
+        let limb_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+
+        let mut limbs = [0u64; 4];
+        let kept = limbs.iter_mut().take(4 - limb_shift);
+        for (index, limb) in kept.enumerate() {
+            let source = index + limb_shift;
+            *limb = self.limbs[source] >> bit_shift;
+            if bit_shift > 0 && source < 3 {
+                *limb |= self.limbs[source + 1] << (64 - bit_shift);
+            }
+        }
+
+        U256 { limbs }
+    }
+}
+
+impl Div for U256 {
+    type Output = Self;
+
+    /// Integer division, rounding down. Panics if `divisor` is zero.
+    fn div(self, divisor: Self) -> Self {
+        assert!(divisor != U256::ZERO, "division by zero");
+        if self < divisor {
+            return U256::ZERO;
+        }
+
+        // Long division, one bit of the quotient at a time from the top
+        let shift = self.bits() - divisor.bits();
+        let mut shifted_divisor = divisor
+            .checked_shl(shift)
+            .expect("divisor is no longer than the dividend");
+        let mut remainder = self;
+        let mut quotient = U256::ZERO;
+        for bit in (0..=shift).rev() {
+            if let Some(difference) = remainder.checked_sub(shifted_divisor) {
+                remainder = difference;
+                quotient.limbs[bit as usize / 64] |= 1 << (bit % 64);
+            }
+            shifted_divisor = shifted_divisor >> 1;
+        }
+
+        quotient
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.limbs.iter().rev().cmp(other.limbs.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Debug for U256 {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "U256({})", crate::hex(&self.to_be_bytes()))
+    }
+}
+
+/// The target a hash has to be at or below to have `difficulty`, which is how
+/// many hashes it takes to find one on average. A difficulty of 1 or less is
+/// met by every hash.
+pub fn target_from_difficulty(difficulty: f64) -> U256 {
+    if difficulty <= 1f64 {
+        return U256::MAX;
+    }
+
+    U256::from_f64(U256::MAX.to_f64() / difficulty)
+}
+
+/// The target whose difficulty is `bonus` lower than that of `target`, where
+/// the difficulty of a target is `U256::MAX` divided by it. Only integers are
+/// used, so every node gets the same target. Targets at a difficulty of 1 or
+/// less are met by every hash.
+pub fn lower_difficulty(target: U256, bonus: u64) -> U256 {
+    let difficulty = U256::MAX / target.max(U256::ONE);
+    let remaining = match difficulty.checked_sub(U256::from_u64(bonus)) {
+        Some(remaining) if remaining > U256::ONE => remaining,
+        _ => return U256::MAX,
+    };
+
+    // target * difficulty / remaining, as target plus the part it is raised
+    // by. The product can't overflow, as bonus is less than the difficulty.
+    let raise = target.mul_div(bonus, 1) / remaining;
+    target.checked_add(raise).unwrap_or(U256::MAX)
+}
+
+/// The difficulty of a target, the inverse of `target_from_difficulty`.
+pub fn difficulty_from_target(target: U256) -> f64 {
+    U256::MAX.to_f64() / target.to_f64().max(1f64)
+}
+
+/// The work a hash shows: how many hashes it takes on average to find one
+/// this low.
+pub fn hash_work(hash: &[u8; 32]) -> f64 {
+    difficulty_from_target(U256::from_be_bytes(*hash))
+}
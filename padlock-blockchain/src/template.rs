@@ -0,0 +1,212 @@
+use bls_signatures::{Serialize, Signature};
+use tracing::debug;
+
+use crate::block::{Block, BlockError, BlockHeader, Entry, HeaderFields};
+use crate::mempool::Mempool;
+use crate::target::U256;
+use crate::{Blockchain, BlockchainError, BlockchainErrorKind};
+
+use std::time::SystemTime;
+
+/// The most bytes of RandomX input a miner may put in a template's block. Room
+/// for it is left when picking entries, so the finished block stays within the
+/// block size cap.
+pub const MAX_RANDOMX_INPUT_SIZE: usize = 32;
+
+// Roughly how many bytes each serialized entry adds on top of its own bytes.
+// Only used to pick entries, the finished block's size is checked exactly.
+const ENTRY_OVERHEAD: usize = 3;
+
+/// A block for the top of the chain which only needs its proof of work.
+///
+/// Miners hash the header's `pow_input` for different RandomX inputs, keyed
+/// by `seed_key`, until they find a hash at or below `target`. Then they turn
+/// the template into a block with `into_block`.
+pub struct BlockTemplate {
+    block: Block,
+    seed_key: [u8; 32],
+    target: U256,
+}
+
+impl BlockTemplate {
+    /// Builds a template on top of `blockchain` using the pending entries of
+    /// `mempool`.
+    ///
+    /// Entries are picked from the highest entry difficulty down, skipping any
+    /// that don't fit in the block or its payload limit, are below the chain's
+    /// minimum entry difficulty or have a version or time locks that aren't
+    /// valid in the block, until the block counts the max allowed entry
+    /// difficulty. As the counted entry difficulty is capped, entries past
+    /// that point wouldn't lower the miner's target.
+    pub fn new(
+        blockchain: &Blockchain,
+        mempool: &Mempool,
+        miner_address: [u8; 32],
+    ) -> Result<Self, BlockchainError> {
+        let info = &blockchain.info;
+        let seed_key = blockchain.seed_key(info.height + 1)?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+            .max(info.past_median_timestamp);
+
+        let fields = HeaderFields {
+            version: blockchain.block_version(info.height + 1)?,
+            previous_hash: info.top_block_hash,
+            height: info.height + 1,
+            timestamp,
+            target: info.target,
+            entry_difficulty_multiplier: info.entry_difficulty_multiplier,
+            max_allowed_entry_difficulty: info.max_allowed_entry_difficulty,
+            min_entry_difficulty: info.min_entry_difficulty,
+            miner_address,
+        };
+        // The merkle root, entry difficulty and signature come later
+        let mut header = BlockHeader::new(fields, [0u8; 28], 0f32, Vec::new());
+
+        // Find how big the block is without any entries, to know how much
+        // room is left for them. The signature is a placeholder of the same
+        // length as the aggregated signature.
+        header.signature = vec![0u8; 96];
+        let empty_block = Block {
+            entries: Vec::new(),
+            header: header.clone(),
+            randomx_input: Vec::new(),
+            hash: [0u8; 32],
+        };
+
+        let mut selected = Vec::new();
+        let mut block_size =
+            empty_block.to_bytes()?.len() + MAX_RANDOMX_INPUT_SIZE;
+        let mut payload_size = 0;
+        let mut entry_difficulty = 0f32;
+
+        let threshold_keys_allowed =
+            blockchain.allows_threshold_keys(info.height + 1)?;
+        for mempool_entry in mempool.entries() {
+            if entry_difficulty >= info.max_allowed_entry_difficulty {
+                break;
+            }
+
+            // The chain's minimum may have risen, the entry's version or keys
+            // may have stopped being valid or the entry expired, since the
+            // entry was added
+            let entry = mempool_entry.entry();
+            let height = info.height + 1;
+            if entry.difficulty()? < info.min_entry_difficulty
+                || !blockchain
+                    .params
+                    .is_entry_version_allowed(entry.version, height)
+                || (entry.threshold_keys.is_some() && !threshold_keys_allowed)
+                || !entry.is_mature(height, info.past_median_timestamp)
+                || entry.is_expired(height, info.past_median_timestamp)
+            {
+                continue;
+            }
+
+            let entry_size = entry.to_bytes()?.len() + ENTRY_OVERHEAD;
+            if block_size + entry_size > info.block_size_cap
+                || payload_size + entry.payload.len()
+                    > blockchain.params.max_block_payload_size
+            {
+                continue;
+            }
+
+            block_size += entry_size;
+            payload_size += entry.payload.len();
+            entry_difficulty += entry.difficulty()? as f32;
+            selected.push(mempool_entry.clone());
+        }
+
+        // The size of each entry was only estimated, so drop the lowest
+        // entries until the block really fits
+        loop {
+            if selected.is_empty() {
+                return Err(BlockchainError::new(
+                    BlockchainErrorKind::NoPendingEntries,
+                ));
+            }
+
+            let signatures = selected
+                .iter()
+                .map(|mempool_entry| {
+                    Signature::from_bytes(mempool_entry.signature())
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(BlockError::from)?;
+            header.signature = bls_signatures::aggregate(&signatures)
+                .map_err(BlockError::from)?
+                .as_bytes();
+
+            let mut block = Block {
+                entries: selected.iter().cloned().map(Entry::from).collect(),
+                header: header.clone(),
+                randomx_input: Vec::new(),
+                hash: [0u8; 32],
+            };
+            block.header.merkle_root = block.calc_merkle_root();
+            block.header.entry_difficulty = block.entry_difficulty()?;
+
+            let block_size = block.to_bytes()?.len() + MAX_RANDOMX_INPUT_SIZE;
+            if block_size > info.block_size_cap {
+                selected.pop();
+                continue;
+            }
+
+            let target = block.header.effective_target();
+
+            debug!(
+                height = block.header.height,
+                entries = block.entries.len(),
+                entry_difficulty = block.header.entry_difficulty,
+                target = ?target,
+                "built block template"
+            );
+
+            return Ok(BlockTemplate {
+                block,
+                seed_key,
+                target,
+            });
+        }
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.block.header
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.block.entries
+    }
+
+    /// The key to hash with, which only changes once per seed epoch.
+    pub fn seed_key(&self) -> &[u8; 32] {
+        &self.seed_key
+    }
+
+    /// The target a hash needs to be at or below for the block to be
+    /// accepted, which is the chain's target raised by what the entries
+    /// contribute.
+    pub fn target(&self) -> U256 {
+        self.target
+    }
+
+    /// Whether `hash` has enough work for this template's block.
+    pub fn meets_target(&self, hash: &[u8; 32]) -> bool {
+        U256::from_be_bytes(*hash) <= self.target
+    }
+
+    /// Finishes the block with the RandomX input that was found and its hash.
+    /// The input must be no longer than `MAX_RANDOMX_INPUT_SIZE`.
+    pub fn into_block(self, randomx_input: Vec<u8>, hash: [u8; 32]) -> Block {
+        debug_assert!(randomx_input.len() <= MAX_RANDOMX_INPUT_SIZE);
+
+        Block {
+            randomx_input,
+            hash,
+            ..self.block
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use bls_signatures::{PrivateKey, PublicKey, Serialize, Signature};
+
+use crate::block::{
+    resolve_public_key_index, BlockError, BlockErrorKind, Entry,
+    PUBLIC_KEY_INDEX_TAG, PUBLIC_KEY_TAG,
+};
+use crate::encoding::Decoder;
+
+/// The most keys threshold keys can name.
+pub const MAX_THRESHOLD_KEYS: usize = 16;
+
+/// The longest threshold keys can be encoded: the threshold, every key with
+/// its tag and every signer, along with their counts.
+pub(crate) const MAX_THRESHOLD_KEYS_SIZE: usize =
+    1 + 1 + MAX_THRESHOLD_KEYS * (1 + 48) + 1 + MAX_THRESHOLD_KEYS;
+
+/// One of the keys that can own an entry, either in full or as the index of a
+/// public key in the chain.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+pub enum OwnerKey {
+    PublicKey(Vec<u8>), // serde can't support arrays past 32
+    Index(u64),
+}
+
+impl OwnerKey {
+    /// Gets the public key, from the database if it is an index.
+    pub fn resolve(&self, db: &rocks::db::DB) -> Result<PublicKey, BlockError> {
+        match self {
+            OwnerKey::PublicKey(public_key) => {
+                Ok(PublicKey::from_bytes(public_key)?)
+            }
+            OwnerKey::Index(index) => resolve_public_key_index(db, *index),
+        }
+    }
+}
+
+/// Keys that own an entry together, any `threshold` of which can sign it.
+///
+/// The entry names which keys sign it in `signers`, and its signature is the
+/// aggregate of theirs, each over the entry's signing message for their own
+/// key. So the signers are picked before the entry is signed, and each of them
+/// signs it with `PartialSignature::sign`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+pub struct ThresholdKeys {
+    pub threshold: u8,
+    pub keys: Vec<OwnerKey>,
+    /// The positions in `keys` of the keys that sign the entry, in increasing
+    /// order. There must be at least `threshold` of them.
+    pub signers: Vec<u8>,
+}
+
+impl ThresholdKeys {
+    pub fn new(threshold: u8, keys: Vec<OwnerKey>, signers: Vec<u8>) -> Self {
+        Self {
+            threshold,
+            keys,
+            signers,
+        }
+    }
+
+    /// Checks that there are no more keys than `MAX_THRESHOLD_KEYS`, that the
+    /// threshold is between one and the number of keys, that no key is named
+    /// twice, and that enough distinct keys sign. An index can only be told
+    /// apart from a full key with the chain, which `resolve_signer_keys` does.
+    pub fn check(&self) -> Result<(), BlockError> {
+        if self.keys.len() > MAX_THRESHOLD_KEYS {
+            return Err(BlockError::new(
+                BlockErrorKind::TooManyThresholdKeys {
+                    maximum: MAX_THRESHOLD_KEYS,
+                    found: self.keys.len(),
+                },
+            ));
+        }
+
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            return Err(BlockError::new(BlockErrorKind::InvalidThreshold {
+                threshold: self.threshold,
+                keys: self.keys.len(),
+            }));
+        }
+
+        let duplicate = self
+            .keys
+            .iter()
+            .enumerate()
+            .any(|(position, key)| self.keys[..position].contains(key));
+        if duplicate {
+            return Err(BlockError::new(BlockErrorKind::DuplicateThresholdKey));
+        }
+
+        let increasing = self.signers.windows(2).all(|pair| pair[0] < pair[1]);
+        let in_range = self
+            .signers
+            .iter()
+            .all(|signer| (*signer as usize) < self.keys.len());
+        if !increasing || !in_range {
+            return Err(BlockError::new(BlockErrorKind::InvalidSigners));
+        }
+
+        if self.signers.len() < self.threshold as usize {
+            return Err(BlockError::new(BlockErrorKind::NotEnoughSigners {
+                threshold: self.threshold,
+                found: self.signers.len(),
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// Gets the public keys of the signers, in the order of `signers`. Every
+    /// key is resolved, so that an index to the same key as another is
+    /// rejected.
+    pub fn resolve_signer_keys(
+        &self,
+        db: &rocks::db::DB,
+    ) -> Result<Vec<PublicKey>, BlockError> {
+        let keys = self
+            .keys
+            .iter()
+            .map(|key| key.resolve(db))
+            .collect::<Result<Vec<PublicKey>, BlockError>>()?;
+
+        let duplicate = keys
+            .iter()
+            .enumerate()
+            .any(|(position, key)| keys[..position].contains(key));
+        if duplicate {
+            return Err(BlockError::new(BlockErrorKind::DuplicateThresholdKey));
+        }
+
+        self.signers
+            .iter()
+            .map(|signer| {
+                keys.get(*signer as usize).copied().ok_or_else(|| {
+                    BlockError::new(BlockErrorKind::InvalidSigners)
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, BlockError> {
+        self.check()?;
+
+        let mut bytes = vec![self.threshold, self.keys.len() as u8];
+        for key in &self.keys {
+            match key {
+                OwnerKey::PublicKey(public_key) => {
+                    bytes.push(PUBLIC_KEY_TAG);
+                    bytes.extend_from_slice(public_key);
+                }
+                OwnerKey::Index(index) => {
+                    bytes.push(PUBLIC_KEY_INDEX_TAG);
+                    bytes.extend_from_slice(&index.to_le_bytes());
+                }
+            }
+        }
+        bytes.push(self.signers.len() as u8);
+        bytes.extend_from_slice(&self.signers);
+
+        Ok(bytes)
+    }
+
+    pub(crate) fn decode(decoder: &mut Decoder) -> Result<Self, BlockError> {
+        let threshold = decoder.get_u8()?;
+
+        let keys_len = decoder.get_u8()? as usize;
+        if keys_len > MAX_THRESHOLD_KEYS {
+            return Err(BlockError::new(
+                BlockErrorKind::TooManyThresholdKeys {
+                    maximum: MAX_THRESHOLD_KEYS,
+                    found: keys_len,
+                },
+            ));
+        }
+
+        let mut keys = Vec::with_capacity(keys_len);
+        for _ in 0..keys_len {
+            keys.push(match decoder.get_u8()? {
+                PUBLIC_KEY_TAG => {
+                    OwnerKey::PublicKey(decoder.get_array::<48>()?.to_vec())
+                }
+                PUBLIC_KEY_INDEX_TAG => OwnerKey::Index(decoder.get_u64()?),
+                found => {
+                    return Err(BlockError::new(
+                        BlockErrorKind::UnknownKeyType { found },
+                    ))
+                }
+            });
+        }
+
+        let signers_len = decoder.get_u8()? as usize;
+        let signers = decoder.get_slice(signers_len)?.to_vec();
+
+        let threshold_keys = Self::new(threshold, keys, signers);
+        threshold_keys.check()?;
+
+        Ok(threshold_keys)
+    }
+}
+
+/// One signer's signature of an entry owned by threshold keys. Once every
+/// signer has made theirs, they are combined into the entry's signature with
+/// `MempoolEntry::combine`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct PartialSignature {
+    /// The signer's position in the entry's keys.
+    pub signer: u8,
+    pub signature: Vec<u8>,
+}
+
+impl PartialSignature {
+    /// Signs `entry`, for the chain with `chain_id`, as the key at position
+    /// `signer` in its threshold keys. Fails if that key isn't one of the
+    /// entry's signers, or is a full public key other than `private_key`'s.
+    pub fn sign(
+        entry: &Entry,
+        signer: u8,
+        chain_id: u32,
+        private_key: &PrivateKey,
+    ) -> Result<Self, BlockError> {
+        let not_a_signer =
+            || BlockError::new(BlockErrorKind::NotASigner { signer });
+
+        let threshold_keys =
+            entry.threshold_keys.as_ref().ok_or_else(not_a_signer)?;
+        if !threshold_keys.signers.contains(&signer) {
+            return Err(not_a_signer());
+        }
+
+        let public_key = private_key.public_key();
+        if let Some(OwnerKey::PublicKey(key)) =
+            threshold_keys.keys.get(signer as usize)
+        {
+            if *key != public_key.as_bytes() {
+                return Err(not_a_signer());
+            }
+        }
+
+        let message = entry.signing_message(chain_id, &public_key)?;
+
+        Ok(Self {
+            signer,
+            signature: private_key.sign(message).as_bytes(),
+        })
+    }
+
+    /// Whether this is a valid signature of `entry` by `public_key`, so a bad
+    /// one can be told apart before they are combined.
+    pub fn verify(
+        &self,
+        entry: &Entry,
+        chain_id: u32,
+        public_key: &PublicKey,
+    ) -> Result<bool, BlockError> {
+        let signature = Signature::from_bytes(&self.signature)?;
+        let message = entry.signing_message(chain_id, public_key)?;
+
+        Ok(public_key.verify(signature, &message))
+    }
+}
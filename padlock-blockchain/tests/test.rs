@@ -1,25 +1,64 @@
-use std::{error::Error, fs, time::SystemTime};
+use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, Mutex};
+use std::{error::Error, fs, slice, time::SystemTime};
 
 use padlock_blockchain::block::Block;
+use padlock_blockchain::block::MAX_ENTRY_SIZE;
+use padlock_blockchain::block::BlockErrorKind;
 use padlock_blockchain::block::BlockHeader;
+use padlock_blockchain::block::HeaderFields;
 use padlock_blockchain::block::Entry;
+use padlock_blockchain::block::{
+    v1_hash, ENTRY_VERSION_1, ENTRY_VERSION_2, ENTRY_VERSION_3,
+    ENTRY_VERSION_4, LOCK_TIME_THRESHOLD, MAX_PAYLOAD_SIZE, V1_HASH_LEN,
+};
 use padlock_blockchain::block::MempoolEntry;
+use padlock_blockchain::deployment::{
+    Deployment, DeploymentId, ThresholdState, VERSIONBITS_TOP_BITS,
+};
+use padlock_blockchain::difficulty::{
+    DifficultyAlgorithm, Lwma, RetargetBlock, SimpleAverage,
+};
+use padlock_blockchain::mempool::{
+    Mempool, MempoolErrorKind, DEFAULT_MEMORY_CAP,
+};
+use padlock_blockchain::miner::Miner;
+use padlock_blockchain::params::{NetworkParams, MAIN_CHAIN_ID};
+use padlock_blockchain::randomx;
+use padlock_blockchain::reward::{Reward, SubsidySchedule};
+use padlock_blockchain::signatures::{self, SignatureCache, SignedMessages};
+use padlock_blockchain::target::{self, U256};
+use padlock_blockchain::template::BlockTemplate;
+use padlock_blockchain::threshold::{OwnerKey, PartialSignature, ThresholdKeys};
+use padlock_blockchain::encoding::EncodingErrorKind;
 use padlock_blockchain::Blockchain;
+use padlock_blockchain::BlockchainInfo;
+use padlock_blockchain::BlockchainError;
+use padlock_blockchain::BlockchainErrorKind;
+use padlock_blockchain::STORAGE_VERSION;
 
-use bls_signatures::{PrivateKey, Serialize};
-use rand::{rngs::OsRng, RngCore};
-use randomx_bindings::{RandomxDataset, RandomxFlags, RandomxVm};
+use bls_signatures::{PrivateKey, PublicKey, Serialize, Signature};
+use groupy::CurveProjective;
+use paired::bls12_381::G1;
+use rand::{rngs::OsRng, rngs::StdRng, Rng, RngCore, SeedableRng};
+use rocks::prelude::*;
+use randomx_bindings::{RandomxCache, RandomxDataset, RandomxFlags, RandomxVm};
 
 // 3 blocks should be the minimum testing amount. If it is less than that, there is no difficulty
 // adjustment
 const TEST_BLOCKS_TO_MINE: usize = 10000;
-const START_DIFFICULTY: f32 = 64f32;
+const START_DIFFICULTY: f64 = 64f64;
+const BLOCK_TIME: u64 = 90;
 
 #[test]
 fn add_one_block() -> Result<(), Box<dyn Error>> {
     let mut blockchain = make_blockchain("./add_one_block_test")?;
 
-    blockchain.add_block(mine_block(&blockchain)?)?;
+    let block = mine_block(&blockchain)?;
+    let hash = block.hash;
+    blockchain.add_block(block)?;
+
+    // Blocks are stored under their prefixed key, not their bare hash
+    assert_eq!(blockchain.get_block(&hash)?.hash, hash);
 
     fs::remove_dir_all("./add_one_block_test")?;
     Ok(())
@@ -71,41 +110,1657 @@ fn block_reorganization() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Mines blocks on one chain, then syncs them onto a second chain as a batch.
+#[test]
+fn add_blocks_in_parallel() -> Result<(), Box<dyn Error>> {
+    let mut mining_blockchain =
+        make_blockchain("./add_blocks_in_parallel_mining_test")?;
+    let mut syncing_blockchain =
+        make_blockchain("./add_blocks_in_parallel_syncing_test")?;
+
+    let mut blocks_bytes = Vec::new();
+    for _ in 0..10 {
+        let block = mine_block(&mining_blockchain)?;
+        blocks_bytes.push(block.to_bytes()?);
+        mining_blockchain.add_block(block)?;
+    }
+
+    let mut blocks = Vec::new();
+    for block_bytes in blocks_bytes.iter().rev() {
+        blocks.push(Block::from_bytes(block_bytes)?);
+    }
+
+    syncing_blockchain.add_blocks(blocks)?;
+
+    assert_eq!(
+        syncing_blockchain.info.top_block_hash,
+        mining_blockchain.info.top_block_hash
+    );
+    assert_eq!(syncing_blockchain.info.height, 10);
+
+    fs::remove_dir_all("./add_blocks_in_parallel_mining_test")?;
+    fs::remove_dir_all("./add_blocks_in_parallel_syncing_test")?;
+    Ok(())
+}
+
+/// A header can be accepted before its entries are known, while entries that
+/// don't match the accepted header are still rejected.
+#[test]
+fn validate_header_then_body() -> Result<(), Box<dyn Error>> {
+    let blockchain = make_blockchain("./validate_header_then_body_test")?;
+
+    let mut block = mine_block(&blockchain)?;
+    blockchain.validate_header(
+        &block.header,
+        &block.randomx_input,
+        &block.hash,
+    )?;
+    blockchain.validate_body(&block)?;
+
+    block.entries.pop();
+    blockchain.validate_header(
+        &block.header,
+        &block.randomx_input,
+        &block.hash,
+    )?;
+
+    let error = blockchain
+        .validate_body(&block)
+        .expect_err("body without one of its entries was accepted");
+    assert!(matches!(
+        error.kind(),
+        BlockchainErrorKind::InvalidMerkleRoot { .. }
+    ));
+
+    fs::remove_dir_all("./validate_header_then_body_test")?;
+    Ok(())
+}
+
+#[test]
+fn rejection_reports_reason() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./rejection_reports_reason_test")?;
+
+    let mut block = mine_block(&blockchain)?;
+    block.header.previous_hash = [1u8; 32];
+    let block_hash = block.hash;
+
+    let error = blockchain
+        .add_block(block)
+        .expect_err("block with the wrong previous hash was accepted");
+
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::BlockPreviousHashWrong {
+            expected: blockchain.info.top_block_hash,
+            found: [1u8; 32],
+        }
+    );
+    assert_eq!(error.block_hash(), Some(&block_hash));
+
+    fs::remove_dir_all("./rejection_reports_reason_test")?;
+    Ok(())
+}
+
+#[test]
+fn mempool_admission() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./mempool_admission_test")?;
+    let mut mempool = Mempool::default();
+
+    let entry = make_entry_spending([1u8; 32])?;
+    mempool.add(entry.clone(), &blockchain)?;
+
+    let error = mempool.add(entry, &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::AlreadyInMempool);
+
+    let error = mempool
+        .add(make_entry_spending([1u8; 32])?, &blockchain)
+        .unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::ConflictsWithPending {
+            coinfile_hash: [1u8; 32]
+        }
+    );
+
+    // An entry spending a coinfile twice could never be in a block
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+    let spends_twice = make_entry_with(&private_key, |entry| {
+        entry.coinfile_hashes = vec![[7u8; 32], [7u8; 32]];
+    })?;
+    let error = mempool.add(spends_twice.clone(), &blockchain).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::DuplicateSpend {
+            coinfile_hash: [7u8; 32]
+        }
+    );
+    let block = mine_block_with_entries(&blockchain, vec![spends_twice])?;
+    assert!(blockchain.add_block(block).is_err());
+
+    let signed_entry = make_entry()?;
+    let mut unsigned_entry = signed_entry.entry().clone();
+    unsigned_entry.output_hash = [2u8; 32];
+    let forged_entry = MempoolEntry::new(
+        with_work(unsigned_entry),
+        signed_entry.signature().to_vec(),
+    );
+    let error = mempool.add(forged_entry, &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::InvalidSignature);
+
+    for _ in 0..4 {
+        mempool.add(make_entry()?, &blockchain)?;
+    }
+
+    let difficulties = mempool
+        .entries()
+        .iter()
+        .map(|mempool_entry| mempool_entry.entry().difficulty())
+        .collect::<Result<Vec<u64>, _>>()?;
+    assert_eq!(difficulties.len(), 5);
+    assert!(difficulties.windows(2).all(|pair| pair[0] >= pair[1]));
+
+    fs::remove_dir_all("./mempool_admission_test")?;
+    Ok(())
+}
+
+#[test]
+fn double_spends_are_rejected() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./double_spend_test")?;
+
+    let spend = make_entry_spending([2u8; 32])?;
+    blockchain.add_block(mine_block_with_entries(&blockchain, vec![spend])?)?;
+    assert!(blockchain.is_coinfile_spent(&[2u8; 32])?);
+
+    let respend = make_entry_spending([2u8; 32])?;
+    let block = mine_block_with_entries(&blockchain, vec![respend])?;
+    let error = blockchain.add_block(block.clone()).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::CoinfileAlreadySpent {
+            entry_index: 0,
+            coinfile_hash: [2u8; 32],
+        }
+    );
+    assert!(blockchain.add_blocks(vec![block]).is_err());
+
+    let entries = vec![
+        make_entry_spending([3u8; 32])?,
+        make_entry_spending([3u8; 32])?,
+    ];
+    let block = mine_block_with_entries(&blockchain, entries)?;
+    let error = blockchain.add_blocks(vec![block]).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::CoinfileAlreadySpent {
+            entry_index: 1,
+            coinfile_hash: [3u8; 32],
+        }
+    );
+    assert!(!blockchain.is_coinfile_spent(&[3u8; 32])?);
+
+    // A hash that is its own version 1 form is still only spent once
+    let mut short_hash = [0u8; 32];
+    short_hash[0] = 9;
+    let spend = make_entry_spending(short_hash)?;
+    Mempool::default().add(spend.clone(), &blockchain)?;
+    blockchain.add_block(mine_block_with_entries(&blockchain, vec![spend])?)?;
+    assert!(blockchain.is_coinfile_spent(&short_hash)?);
+
+    fs::remove_dir_all("./double_spend_test")?;
+    Ok(())
+}
+
+#[test]
+fn min_entry_difficulty() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./min_entry_difficulty_test")?;
+    let mut mempool = Mempool::new(DEFAULT_MEMORY_CAP, 0);
+
+    let entry = make_entry()?;
+    let difficulty = entry.entry().difficulty()?;
+    blockchain.info.min_entry_difficulty = difficulty + 1;
+
+    // The chain's minimum applies even when the mempool's is lower
+    let error = mempool.add(entry.clone(), &blockchain).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::NotEnoughWork {
+            minimum: difficulty + 1,
+            found: difficulty,
+        }
+    );
+
+    let block = mine_block_with_entries(&blockchain, vec![entry])?;
+    let error = blockchain.add_block(block.clone()).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::EntryNotEnoughWork {
+            entry_index: 0,
+            minimum: difficulty + 1,
+            found: difficulty,
+        }
+    );
+
+    let mut block = block;
+    block.header.min_entry_difficulty = 0;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::BlockMinEntryDifficultyWrong {
+            expected: difficulty + 1,
+            found: 0,
+        }
+    );
+
+    fs::remove_dir_all("./min_entry_difficulty_test")?;
+    Ok(())
+}
+
+#[test]
+fn rogue_key_aggregates_are_rejected() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain_with_params(
+        "./rogue_key_aggregates_test",
+        threshold_keys_params(),
+    )?;
+    activate_threshold_keys(&mut blockchain)?;
+    let mut mempool = Mempool::default();
+
+    // The attacker makes up a key from their own minus the victim's, so the
+    // two add up to the attacker's key
+    let victim_key = PrivateKey::generate(&mut OsRng::default());
+    let attacker_key = PrivateKey::generate(&mut OsRng::default());
+    let mut rogue_key = G1::from(attacker_key.public_key());
+    rogue_key.sub_assign(&G1::from(victim_key.public_key()));
+    let rogue_key = PublicKey::from(rogue_key);
+
+    let entry = make_unsigned_entry(&attacker_key, |entry| {
+        entry.public_key = None;
+        entry.threshold_keys = Some(ThresholdKeys::new(
+            2,
+            vec![
+                OwnerKey::PublicKey(victim_key.public_key().as_bytes()),
+                OwnerKey::PublicKey(rogue_key.as_bytes()),
+            ],
+            vec![0, 1],
+        ));
+    });
+
+    // Were both keys to sign the same message, the attacker's one signature
+    // would be the aggregate of both of theirs
+    let shared_message =
+        [MAIN_CHAIN_ID.to_le_bytes().to_vec(), entry.to_bytes()?].concat();
+    let signature = attacker_key.sign(&shared_message);
+    let aggregate = bls_signatures::aggregate(&[signature])?;
+    let mut aggregate_key = G1::from(victim_key.public_key());
+    aggregate_key.add_assign(&G1::from(rogue_key));
+    assert!(PublicKey::from(aggregate_key).verify(aggregate, &shared_message));
+
+    let rogue_entry = MempoolEntry::new(entry, aggregate.as_bytes());
+    let error = mempool.add(rogue_entry.clone(), &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::InvalidSignature);
+
+    let block = mine_block_with_entries(&blockchain, vec![rogue_entry])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::InvalidSignature { entry_index: None }
+    );
+
+    fs::remove_dir_all("./rogue_key_aggregates_test")?;
+    Ok(())
+}
+
+#[test]
+fn entries_are_bound_to_chain() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./entries_bound_to_chain_test")?;
+    let mut mempool = Mempool::default();
+
+    let test_params = NetworkParams {
+        chain_id: MAIN_CHAIN_ID + 1,
+        ..NetworkParams::default()
+    };
+    let mut test_blockchain = make_blockchain_with_params(
+        "./entries_bound_to_chain_test_net",
+        test_params.clone(),
+    )?;
+
+    // An entry signed for the test network can't be replayed on the main one
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+    let entry = make_unsigned_entry(&private_key, |_| {});
+    let test_entry =
+        MempoolEntry::sign(entry, test_params.chain_id, &private_key)?;
+
+    let error = mempool.add(test_entry.clone(), &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::InvalidSignature);
+
+    let block = mine_block_with_entries(&blockchain, vec![test_entry.clone()])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::InvalidSignature { entry_index: None }
+    );
+
+    // It is valid on the network it was signed for
+    Mempool::default().add(test_entry.clone(), &test_blockchain)?;
+    let block = mine_block_with_entries(&test_blockchain, vec![test_entry])?;
+    test_blockchain.add_block(block)?;
+
+    fs::remove_dir_all("./entries_bound_to_chain_test")?;
+    fs::remove_dir_all("./entries_bound_to_chain_test_net")?;
+    Ok(())
+}
+
+#[test]
+fn entry_versions() -> Result<(), Box<dyn Error>> {
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+    let coinfile_hash = [7u8; 32];
+
+    // Version 1 entries keep their short hashes on the wire
+    let v1_entry = make_entry_with(&private_key, |entry| {
+        entry.version = ENTRY_VERSION_1;
+        entry.coinfile_hashes = vec![v1_hash(&coinfile_hash)];
+    })?;
+    let bytes = v1_entry.entry().to_bytes()?;
+    assert_eq!(bytes[0], ENTRY_VERSION_1);
+    assert_eq!(&Entry::from_bytes(&bytes)?, v1_entry.entry());
+
+    let mut wide_entry = v1_entry.entry().clone();
+    wide_entry.output_hash = [1u8; 32];
+    assert_eq!(
+        wide_entry.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::HashTooLong {
+            maximum: V1_HASH_LEN
+        }
+    );
+
+    // Version 1 only at height 1, either at height 2, version 2 only after
+    let params = NetworkParams {
+        entry_v2_height: 2,
+        entry_v1_end_height: 3,
+        ..NetworkParams::default()
+    };
+    let mut blockchain =
+        make_blockchain_with_params("./entry_versions_test", params)?;
+
+    let v2_entry = make_entry_with(&private_key, |entry| {
+        entry.version = ENTRY_VERSION_2;
+    })?;
+    let error = Mempool::default()
+        .add(v2_entry.clone(), &blockchain)
+        .unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::EntryVersionNotAllowed {
+            version: ENTRY_VERSION_2
+        }
+    );
+
+    let block = mine_block_with_entries(&blockchain, vec![v2_entry])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::EntryVersionNotAllowed {
+            entry_index: 0,
+            version: ENTRY_VERSION_2
+        }
+    );
+
+    Mempool::default().add(v1_entry.clone(), &blockchain)?;
+    let block = mine_block_with_entries(&blockchain, vec![v1_entry])?;
+    blockchain.add_block(block)?;
+
+    // A version 2 entry can't spend the coinfile again by its full hash
+    let error = Mempool::default()
+        .add(make_entry_spending(coinfile_hash)?, &blockchain)
+        .unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::CoinfileAlreadySpent {
+            coinfile_hash: v1_hash(&coinfile_hash)
+        }
+    );
+
+    let entries = vec![
+        make_entry_with(&private_key, |entry| {
+            entry.version = ENTRY_VERSION_1;
+            entry.coinfile_hashes = vec![v1_hash(&[8u8; 32])];
+        })?,
+        make_entry()?,
+    ];
+    blockchain.add_block(mine_block_with_entries(&blockchain, entries)?)?;
+
+    let v1_entry = make_entry_with(&private_key, |entry| {
+        entry.version = ENTRY_VERSION_1;
+        entry.coinfile_hashes = vec![v1_hash(&[9u8; 32])];
+    })?;
+    let error = Mempool::default()
+        .add(v1_entry.clone(), &blockchain)
+        .unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::EntryVersionNotAllowed {
+            version: ENTRY_VERSION_1
+        }
+    );
+
+    let block = mine_block_with_entries(&blockchain, vec![v1_entry])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::EntryVersionNotAllowed {
+            entry_index: 0,
+            version: ENTRY_VERSION_1
+        }
+    );
+
+    fs::remove_dir_all("./entry_versions_test")?;
+    Ok(())
+}
+
+#[test]
+fn entry_payloads() -> Result<(), Box<dyn Error>> {
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+
+    let entry = make_entry_with(&private_key, |entry| {
+        entry.payload = b"text/plain;memo".to_vec();
+    })?;
+    let bytes = entry.entry().to_bytes()?;
+    assert_eq!(&Entry::from_bytes(&bytes)?, entry.entry());
+
+    // Only version 3 entries carry one, and no longer than any entry can
+    // encode
+    let mut v2_entry = entry.entry().clone();
+    v2_entry.version = ENTRY_VERSION_2;
+    assert_eq!(
+        v2_entry.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::PayloadNotSupported
+    );
+
+    let mut long_entry = entry.entry().clone();
+    long_entry.payload = vec![0u8; MAX_PAYLOAD_SIZE + 1];
+    assert_eq!(
+        long_entry.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::PayloadTooLong {
+            maximum: MAX_PAYLOAD_SIZE,
+            found: MAX_PAYLOAD_SIZE + 1
+        }
+    );
+
+    let params = NetworkParams {
+        max_entry_payload_size: 16,
+        max_block_payload_size: 24,
+        ..NetworkParams::default()
+    };
+    let mut blockchain =
+        make_blockchain_with_params("./entry_payloads_test", params)?;
+
+    // The payload is covered by the signature and the merkle root
+    let mut altered_entry = entry.entry().clone();
+    altered_entry.payload = b"text/plain;memo!".to_vec();
+    let altered_entry = MempoolEntry::new(
+        with_work(altered_entry),
+        entry.signature().to_vec(),
+    );
+    let error = Mempool::default()
+        .add(altered_entry, &blockchain)
+        .unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::InvalidSignature);
+
+    let block = mine_block_with_entries(&blockchain, vec![entry.clone()])?;
+    let mut altered_block = block.clone();
+    altered_block.entries[0].payload.push(b'!');
+    assert_ne!(altered_block.calc_merkle_root(), block.header.merkle_root);
+
+    Mempool::default().add(entry, &blockchain)?;
+    blockchain.add_block(block)?;
+
+    // The network limits each entry's payload
+    let long_entry = make_entry_with(&private_key, |entry| {
+        entry.payload = vec![0u8; 17];
+    })?;
+    let error = Mempool::default()
+        .add(long_entry.clone(), &blockchain)
+        .unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::PayloadTooBig {
+            maximum: 16,
+            found: 17
+        }
+    );
+
+    let block = mine_block_with_entries(&blockchain, vec![long_entry])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::EntryPayloadTooBig {
+            entry_index: 0,
+            maximum: 16,
+            found: 17
+        }
+    );
+
+    // And the payloads of a block's entries together
+    let entries = vec![
+        make_entry_with(&private_key, |entry| entry.payload = vec![1u8; 16])?,
+        make_entry_with(&private_key, |entry| entry.payload = vec![2u8; 16])?,
+    ];
+    let block = mine_block_with_entries(&blockchain, entries.clone())?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::BlockPayloadTooBig {
+            maximum: 24,
+            found: 32
+        }
+    );
+
+    // Templates leave out the entries that don't fit
+    let mut mempool = Mempool::default();
+    for entry in entries {
+        mempool.add(entry, &blockchain)?;
+    }
+    blockchain.info.max_allowed_entry_difficulty = f32::MAX;
+    let template = BlockTemplate::new(&blockchain, &mempool, [0u8; 32])?;
+    assert_eq!(template.entries().len(), 1);
+
+    fs::remove_dir_all("./entry_payloads_test")?;
+    Ok(())
+}
+
+#[test]
+fn entry_time_locks() -> Result<(), Box<dyn Error>> {
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+
+    let entry = make_entry_with(&private_key, |entry| {
+        entry.valid_after = Some(1);
+        entry.valid_until = Some(2);
+    })?;
+    let bytes = entry.entry().to_bytes()?;
+    assert_eq!(&Entry::from_bytes(&bytes)?, entry.entry());
+
+    let mut v3_entry = entry.entry().clone();
+    v3_entry.version = ENTRY_VERSION_3;
+    assert_eq!(
+        v3_entry.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::TimeLocksNotSupported
+    );
+
+    let mut blockchain = make_blockchain("./entry_time_locks_test")?;
+    let mut mempool = Mempool::default();
+
+    // Valid after height 1, so not in the block at height 1
+    let error = mempool.add(entry.clone(), &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::NotYetValid);
+
+    let block = mine_block_with_entries(&blockchain, vec![entry.clone()])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::EntryNotYetValid { entry_index: 0 }
+    );
+
+    blockchain.add_block(mine_block(&blockchain)?)?;
+
+    // Valid until height 2, so it's dropped once that block goes by without
+    // it
+    mempool.add(entry.clone(), &blockchain)?;
+
+    let block = mine_block(&blockchain)?;
+    blockchain.add_block(block.clone())?;
+    mempool.block_connected(&block);
+    mempool.remove_expired(&blockchain);
+    assert!(mempool.is_empty());
+
+    let error = mempool.add(entry.clone(), &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::Expired);
+
+    let block = mine_block_with_entries(&blockchain, vec![entry])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::EntryExpired { entry_index: 0 }
+    );
+
+    // Locks from the threshold on are compared with the median time past
+    let median_timestamp = blockchain.info.past_median_timestamp;
+    assert!(median_timestamp >= LOCK_TIME_THRESHOLD);
+
+    let tomorrow = median_timestamp + 24 * 60 * 60;
+    let entry = make_entry_with(&private_key, |entry| {
+        entry.valid_after = Some(tomorrow);
+    })?;
+    let error = mempool.add(entry, &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::NotYetValid);
+
+    let yesterday = median_timestamp - 24 * 60 * 60;
+    let entry = make_entry_with(&private_key, |entry| {
+        entry.valid_until = Some(yesterday);
+    })?;
+    let error = mempool.add(entry, &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::Expired);
+
+    let entry = make_entry_with(&private_key, |entry| {
+        entry.valid_after = Some(yesterday);
+        entry.valid_until = Some(tomorrow);
+    })?;
+    mempool.add(entry.clone(), &blockchain)?;
+    let block = mine_block_with_entries(&blockchain, vec![entry])?;
+    blockchain.add_block(block)?;
+
+    fs::remove_dir_all("./entry_time_locks_test")?;
+    Ok(())
+}
+
+#[test]
+fn median_time_past() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./median_time_past_test")?;
+
+    let start = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs()
+        - 60 * 60;
+    for offset in &[0, 600, 900] {
+        let entries = vec![make_entry()?];
+        let timestamp = start + offset;
+        let block = mine_block_at(&blockchain, entries, [0u8; 32], timestamp)?;
+        blockchain.add_block(block)?;
+    }
+
+    // Neither the oldest nor the latest of the timestamps
+    assert_eq!(blockchain.info.past_median_timestamp, start + 600);
+
+    let entries = vec![make_entry()?];
+    let block = mine_block_at(&blockchain, entries, [0u8; 32], start + 599)?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::BlockTimestampTooEarly {
+            minimum: start + 600,
+            found: start + 599,
+        }
+    );
+
+    fs::remove_dir_all("./median_time_past_test")?;
+    Ok(())
+}
+
+#[test]
+fn threshold_entries() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain_with_params(
+        "./threshold_entries_test",
+        threshold_keys_params(),
+    )?;
+    let mut mempool = Mempool::default();
+
+    let private_keys: Vec<PrivateKey> = (0..3)
+        .map(|_| PrivateKey::generate(&mut OsRng::default()))
+        .collect();
+
+    // Two of three keys own the entry, and the first and last sign it
+    let keys: Vec<OwnerKey> = private_keys
+        .iter()
+        .map(|private_key| {
+            OwnerKey::PublicKey(private_key.public_key().as_bytes())
+        })
+        .collect();
+    let entry = make_unsigned_entry(&private_keys[0], |entry| {
+        entry.public_key = None;
+        entry.threshold_keys = Some(ThresholdKeys::new(2, keys, vec![0, 2]));
+    });
+    let bytes = entry.to_bytes()?;
+    assert_eq!(Entry::from_bytes(&bytes)?, entry);
+
+    let mut v4_entry = entry.clone();
+    v4_entry.version = ENTRY_VERSION_4;
+    assert_eq!(
+        v4_entry.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::ThresholdKeysNotSupported
+    );
+
+    // Older versions don't know the key type
+    let mut v4_bytes = bytes.clone();
+    v4_bytes[0] = ENTRY_VERSION_4;
+    assert_eq!(
+        Entry::from_bytes(&v4_bytes).unwrap_err().kind(),
+        &BlockErrorKind::UnknownKeyType { found: 0x02 }
+    );
+
+    // An entry has exactly one kind of key
+    let mut two_keys = entry.clone();
+    two_keys.public_key = Some(private_keys[0].public_key().as_bytes());
+    assert_eq!(
+        two_keys.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::MultipleKeys
+    );
+
+    // A key can't be named twice to make up the threshold, whether in full
+    // or by an index to the same key
+    let mut duplicate = entry.clone();
+    duplicate.threshold_keys.as_mut().unwrap().keys[1] =
+        OwnerKey::PublicKey(private_keys[0].public_key().as_bytes());
+    assert_eq!(
+        duplicate.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::DuplicateThresholdKey
+    );
+
+    blockchain.db.put(
+        WriteOptions::default_instance(),
+        &[&[0x04][..], &0u64.to_le_bytes()].concat(),
+        &private_keys[0].public_key().as_bytes(),
+    )?;
+    duplicate.threshold_keys.as_mut().unwrap().keys[1] = OwnerKey::Index(0);
+    let error = duplicate.resolve_signer_keys(&blockchain.db).unwrap_err();
+    assert_eq!(error.kind(), &BlockErrorKind::DuplicateThresholdKey);
+
+    let mut too_few = entry.clone();
+    too_few.threshold_keys.as_mut().unwrap().signers = vec![1];
+    assert_eq!(
+        too_few.to_bytes().unwrap_err().kind(),
+        &BlockErrorKind::NotEnoughSigners {
+            threshold: 2,
+            found: 1
+        }
+    );
+
+    // Each signer signs on their own, then the signatures are combined
+    let first =
+        PartialSignature::sign(&entry, 0, MAIN_CHAIN_ID, &private_keys[0])?;
+    let last =
+        PartialSignature::sign(&entry, 2, MAIN_CHAIN_ID, &private_keys[2])?;
+    assert!(first.verify(
+        &entry,
+        MAIN_CHAIN_ID,
+        &private_keys[0].public_key()
+    )?);
+
+    let error =
+        PartialSignature::sign(&entry, 1, MAIN_CHAIN_ID, &private_keys[1])
+            .unwrap_err();
+    assert_eq!(error.kind(), &BlockErrorKind::NotASigner { signer: 1 });
+    let error =
+        PartialSignature::sign(&entry, 2, MAIN_CHAIN_ID, &private_keys[0])
+            .unwrap_err();
+    assert_eq!(error.kind(), &BlockErrorKind::NotASigner { signer: 2 });
+
+    let error = MempoolEntry::combine(entry.clone(), slice::from_ref(&first))
+        .unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockErrorKind::MissingPartialSignature { signer: 2 }
+    );
+
+    // Threshold keys can't own entries until their deployment is active
+    let signed_entry =
+        MempoolEntry::combine(entry.clone(), &[first.clone(), last.clone()])?;
+    let error = mempool.add(signed_entry.clone(), &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::ThresholdKeysNotActive);
+
+    let block = mine_block_with_entries(&blockchain, vec![signed_entry])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::ThresholdKeysNotActive { entry_index: 0 }
+    );
+
+    activate_threshold_keys(&mut blockchain)?;
+
+    // Only one signer's signature isn't enough
+    let unfinished = MempoolEntry::new(entry.clone(), first.signature.clone());
+    let error = mempool.add(unfinished.clone(), &blockchain).unwrap_err();
+    assert_eq!(error.kind(), &MempoolErrorKind::InvalidSignature);
+
+    let block = mine_block_with_entries(&blockchain, vec![unfinished])?;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::InvalidSignature { entry_index: None }
+    );
+
+    // Combined, the entry is valid alongside single key entries
+    let signed_entry = MempoolEntry::combine(entry, &[last, first])?;
+    mempool.add(signed_entry.clone(), &blockchain)?;
+
+    let entries = vec![signed_entry, make_entry()?];
+    let block = mine_block_with_entries(&blockchain, entries)?;
+    blockchain.add_block(block)?;
+
+    fs::remove_dir_all("./threshold_entries_test")?;
+    Ok(())
+}
+
+#[test]
+fn signature_cache() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./signature_cache_test")?;
+    let mut mempool = Mempool::default();
+
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+    let public_key = private_key.public_key();
+    let first = MempoolEntry::sign(
+        make_unsigned_entry(&private_key, |_| {}),
+        MAIN_CHAIN_ID,
+        &private_key,
+    )?;
+    let second = MempoolEntry::sign(
+        make_unsigned_entry(&private_key, |_| {}),
+        MAIN_CHAIN_ID,
+        &private_key,
+    )?;
+
+    mempool.add(first.clone(), &blockchain)?;
+    mempool.add(second.clone(), &blockchain)?;
+    let message = first.entry().signing_message(MAIN_CHAIN_ID, &public_key)?;
+    assert!(blockchain.signature_cache.get(&public_key, &message).is_some());
+    assert_eq!(blockchain.signature_cache.len(), 2);
+
+    // Every signature being cached doesn't let a wrong aggregate through
+    let mut block = mine_block_with_entries(&blockchain, vec![first.clone()])?;
+    block.header.signature = second.signature().to_vec();
+    let (randomx_input, hash) = find_randomx_input(
+        &block.header,
+        &blockchain.seed_key(block.header.height)?,
+    )?;
+    block.randomx_input = randomx_input;
+    block.hash = hash;
+
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::InvalidSignature { entry_index: None }
+    );
+
+    blockchain.add_block(mine_block_with_entries(&blockchain, vec![first])?)?;
+
+    // The cache is bounded, dropping the oldest signatures first
+    let cache = SignatureCache::new(1);
+    cache.insert(&public_key, b"first", private_key.sign(b"first"));
+    cache.insert(&public_key, b"second", private_key.sign(b"second"));
+    assert!(cache.get(&public_key, b"first").is_none());
+    assert!(cache.get(&public_key, b"second").is_some());
+
+    fs::remove_dir_all("./signature_cache_test")?;
+    Ok(())
+}
+
+#[test]
+fn subsidy_schedule() {
+    let schedule = SubsidySchedule::new(100, 2, 30);
+    let rewards: Vec<u64> =
+        (1..=6).map(|height| schedule.reward(height)).collect();
+    assert_eq!(rewards, vec![100, 100, 50, 50, 30, 30]);
+
+    let schedule = SubsidySchedule::new(100, 1, 0);
+    assert_eq!(schedule.reward(8), 0);
+    assert_eq!(schedule.reward(u64::MAX), 0);
+
+    // Without halvings the reward never changes
+    let schedule = SubsidySchedule::new(100, 0, 0);
+    assert_eq!(schedule.reward(u64::MAX), 100);
+}
+
+#[test]
+fn reward_ledger() -> Result<(), Box<dyn Error>> {
+    let params = NetworkParams {
+        subsidy: SubsidySchedule::new(100, 2, 30),
+        ..NetworkParams::default()
+    };
+    let mut blockchain =
+        make_blockchain_with_params("./reward_ledger_test", params)?;
+
+    let alice = [1u8; 32];
+    let bob = [2u8; 32];
+    for miner_address in [alice, bob, alice] {
+        let entries = vec![make_entry()?];
+        let block = mine_block_for(&blockchain, entries, miner_address)?;
+        blockchain.add_block(block)?;
+    }
+
+    assert_eq!(blockchain.balance(&alice)?, 150);
+    assert_eq!(blockchain.balance(&bob)?, 100);
+    assert_eq!(blockchain.balance(&[3u8; 32])?, 0);
+
+    let history = blockchain.reward_history(&alice)?;
+    let heights: Vec<u64> =
+        history.iter().map(|reward| reward.height).collect();
+    assert_eq!(heights, vec![1, 3]);
+    assert_eq!(
+        history[1],
+        Reward {
+            height: 3,
+            block_hash: blockchain.info.top_block_hash,
+            amount: 50,
+        }
+    );
+
+    // Disconnecting a block takes its reward back
+    blockchain.del_top_block()?;
+    assert_eq!(blockchain.balance(&alice)?, 100);
+    assert_eq!(blockchain.reward_history(&alice)?.len(), 1);
+
+    let block = mine_block_for(&blockchain, vec![make_entry()?], bob)?;
+    blockchain.add_block(block)?;
+    assert_eq!(blockchain.balance(&bob)?, 150);
+    assert_eq!(blockchain.reward_history(&bob)?.len(), 2);
+
+    fs::remove_dir_all("./reward_ledger_test")?;
+    Ok(())
+}
+
+#[test]
+fn batched_signatures() -> Result<(), Box<dyn Error>> {
+    let keys: Vec<PrivateKey> = (0..4)
+        .map(|_| PrivateKey::generate(&mut OsRng::default()))
+        .collect();
+    let signed_messages = |keys: &[PrivateKey], messages: &[&[u8]]| {
+        let signatures: Vec<Signature> = keys
+            .iter()
+            .zip(messages)
+            .map(|(key, message)| key.sign(message))
+            .collect();
+
+        SignedMessages {
+            signature: bls_signatures::aggregate(&signatures).unwrap(),
+            public_keys: keys.iter().map(PrivateKey::public_key).collect(),
+            messages: messages.iter().map(|message| message.to_vec()).collect(),
+        }
+    };
+
+    let first = signed_messages(&keys[..2], &[b"a", b"b"]);
+    let second = signed_messages(&keys[2..], &[b"c", b"d"]);
+    assert!(signatures::verify_batch(&[&first, &second]));
+    assert!(signatures::verify_batch(&[]));
+
+    let mut invalid = signed_messages(&keys[2..], &[b"c", b"d"]);
+    invalid.signature = keys[2].sign(b"c");
+    assert!(!signatures::verify_batch(&[&first, &invalid]));
+
+    // Swapping the signatures leaves their sum valid, but each is invalid
+    let mut first_swapped = signed_messages(&keys[..2], &[b"a", b"b"]);
+    let mut second_swapped = signed_messages(&keys[2..], &[b"c", b"d"]);
+    first_swapped.signature = second.signature;
+    second_swapped.signature = first.signature;
+
+    let cache = SignatureCache::default();
+    assert!(!first_swapped.verify(&cache));
+    assert!(!second_swapped.verify(&cache));
+    assert!(!signatures::verify_batch(&[&first_swapped, &second_swapped]));
+
+    Ok(())
+}
+
+#[test]
+fn mempool_eviction() -> Result<(), Box<dyn Error>> {
+    let blockchain = make_blockchain("./mempool_eviction_test")?;
+
+    let mut entries = Vec::new();
+    for _ in 0..6 {
+        entries.push(make_entry()?);
+    }
+
+    let entry_size =
+        entries[0].entry().to_bytes()?.len() + entries[0].signature().len();
+    let memory_cap = entry_size * 3;
+    let mut mempool = Mempool::new(memory_cap, 0);
+
+    let mut lowest_kept = u64::MAX;
+    let mut highest_dropped = 0;
+    for entry in entries {
+        let difficulty = entry.entry().difficulty()?;
+        match mempool.add(entry, &blockchain) {
+            Ok(()) => {}
+            Err(error) => {
+                assert_eq!(error.kind(), &MempoolErrorKind::MempoolFull);
+                highest_dropped = highest_dropped.max(difficulty);
+            }
+        }
+    }
+
+    for mempool_entry in mempool.entries() {
+        lowest_kept = lowest_kept.min(mempool_entry.entry().difficulty()?);
+    }
+
+    assert!(mempool.memory_used() <= memory_cap);
+    assert!(mempool.len() < 6);
+    assert!(lowest_kept >= highest_dropped);
+
+    fs::remove_dir_all("./mempool_eviction_test")?;
+    Ok(())
+}
+
+/// Entries leave the mempool when their block is connected, and come back when
+/// it is disconnected.
+#[test]
+fn mempool_follows_blocks() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./mempool_follows_blocks_test")?;
+    let mut mempool = Mempool::default();
+
+    mempool.add(make_entry_spending([3u8; 32])?, &blockchain)?;
+    mempool.add(make_entry()?, &blockchain)?;
+
+    let entries = mempool.entries().into_iter().cloned().collect();
+    let block = mine_block_with_entries(&blockchain, entries)?;
+
+    blockchain.add_block(block.clone())?;
+    mempool.block_connected(&block);
+    assert!(mempool.is_empty());
+
+    let error = mempool
+        .add(make_entry_spending([3u8; 32])?, &blockchain)
+        .unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &MempoolErrorKind::CoinfileAlreadySpent {
+            coinfile_hash: [3u8; 32]
+        }
+    );
+
+    let block = blockchain.del_top_block()?;
+    mempool.block_disconnected(&block, &blockchain);
+    assert_eq!(mempool.len(), 2);
+
+    fs::remove_dir_all("./mempool_follows_blocks_test")?;
+    Ok(())
+}
+
+#[test]
+fn block_template() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./block_template_test")?;
+    let mut mempool = Mempool::default();
+
+    for _ in 0..3 {
+        mempool.add(make_entry()?, &blockchain)?;
+    }
+    let best_entry: Entry = mempool.entries()[0].clone().into();
+
+    // Each entry reaches the max allowed entry difficulty on its own, so only
+    // the best one is needed
+    let template = BlockTemplate::new(&blockchain, &mempool, [0u8; 32])?;
+    assert_eq!(template.entries(), &[best_entry]);
+    assert_eq!(
+        template.header().entry_difficulty,
+        blockchain.info.max_allowed_entry_difficulty
+    );
+
+    assert_eq!(template.target(), template.header().effective_target());
+
+    let (randomx_input, hash) =
+        find_randomx_input(template.header(), template.seed_key())?;
+    assert!(template.meets_target(&hash));
+    blockchain.add_block(template.into_block(randomx_input, hash))?;
+
+    blockchain.info.block_size_cap = 100;
+    let error = BlockTemplate::new(&blockchain, &mempool, [0u8; 32])
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), &BlockchainErrorKind::NoPendingEntries);
+
+    fs::remove_dir_all("./block_template_test")?;
+    Ok(())
+}
+
+#[test]
+fn miner_mines_from_mempool() -> Result<(), Box<dyn Error>> {
+    let blockchain =
+        Arc::new(Mutex::new(make_blockchain("./miner_mines_from_mempool")?));
+    let mempool = Arc::new(Mutex::new(Mempool::default()));
+
+    {
+        let blockchain = blockchain.lock().unwrap();
+        mempool.lock().unwrap().add(make_entry()?, &blockchain)?;
+    }
+
+    let mut miner =
+        Miner::new(Arc::clone(&blockchain), Arc::clone(&mempool), [0u8; 32], 4);
+    let stop = AtomicBool::new(false);
+
+    let hash = miner.mine_block(&stop)?.expect("miner wasn't stopped");
+    assert_eq!(blockchain.lock().unwrap().info.top_block_hash, hash);
+    assert_eq!(blockchain.lock().unwrap().info.height, 1);
+    assert!(mempool.lock().unwrap().is_empty());
+
+    stop.store(true, Ordering::Relaxed);
+    assert_eq!(miner.mine_block(&stop)?, None);
+
+    fs::remove_dir_all("./miner_mines_from_mempool")?;
+    Ok(())
+}
+
+#[test]
+fn miner_drops_rejected_entries() -> Result<(), Box<dyn Error>> {
+    let blockchain =
+        Arc::new(Mutex::new(make_blockchain("./miner_drops_rejected_test")?));
+    let mempool = Arc::new(Mutex::new(Mempool::default()));
+
+    // The coinfile is spent by a block the mempool never hears about, so the
+    // pending entry spending it can no longer be in a block
+    {
+        let mut blockchain = blockchain.lock().unwrap();
+        let mut mempool = mempool.lock().unwrap();
+        mempool.add(make_entry_spending([4u8; 32])?, &blockchain)?;
+
+        let spend = make_entry_spending([4u8; 32])?;
+        let block = mine_block_with_entries(&blockchain, vec![spend])?;
+        blockchain.add_block(block)?;
+
+        mempool.add(make_entry()?, &blockchain)?;
+    }
+
+    let mut miner =
+        Miner::new(Arc::clone(&blockchain), Arc::clone(&mempool), [0u8; 32], 4);
+    let stop = AtomicBool::new(false);
+
+    let hash = miner.mine_block(&stop)?.expect("miner wasn't stopped");
+    assert_eq!(blockchain.lock().unwrap().info.top_block_hash, hash);
+    assert_eq!(blockchain.lock().unwrap().info.height, 2);
+    assert!(mempool.lock().unwrap().is_empty());
+
+    fs::remove_dir_all("./miner_drops_rejected_test")?;
+    Ok(())
+}
+
+/// Databases written before the storage version was recorded hold entries
+/// that can't be decoded, so they are refused rather than misread.
+#[test]
+fn old_databases_are_refused() -> Result<(), Box<dyn Error>> {
+    let dir = "./old_database_test";
+    let _ = fs::remove_dir_all(dir);
+
+    let options = Options::default()
+        .map_db_options(|db_options| db_options.create_if_missing(true));
+    let db = DB::open(options, dir)?;
+    db.put(
+        WriteOptions::default_instance(),
+        b"blockchain_info",
+        &BlockchainInfo::default().to_bytes(),
+    )?;
+    drop(db);
+
+    let error = Blockchain::new(dir).err().ok_or("old database opened")?;
+    let error = error.downcast_ref::<BlockchainError>().ok_or("wrong error")?;
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::StorageVersionMismatch {
+            expected: STORAGE_VERSION,
+            found: 0,
+        }
+    );
+
+    // A new chain records the current version, so it opens again
+    drop(make_blockchain(dir)?);
+    Blockchain::new(dir)?;
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+/// Decoding keeps the stored hash, leaving it to validation to catch a wrong
+/// one.
+#[test]
+fn decoding_keeps_stored_hash() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = make_blockchain("./decoding_keeps_stored_hash")?;
+
+    let block = mine_block(&blockchain)?;
+    let decoded_block = Block::from_bytes(&block.to_bytes()?)?;
+    assert_eq!(decoded_block.hash, block.hash);
+    let seed_key = blockchain.seed_key(block.header.height)?;
+    assert!(decoded_block.is_hash_valid(&seed_key)?);
+
+    let mut tampered_block = block.clone();
+    tampered_block.hash[31] ^= 0xff;
+    let decoded_block = Block::from_bytes(&tampered_block.to_bytes()?)?;
+    assert_eq!(decoded_block.hash, tampered_block.hash);
+    assert!(!decoded_block.is_hash_valid(&seed_key)?);
+
+    let error = blockchain.add_block(decoded_block).unwrap_err();
+    assert!(matches!(
+        error.kind(),
+        BlockchainErrorKind::InvalidHash { .. }
+    ));
+
+    let hash = block.hash;
+    blockchain.add_block(block)?;
+    assert_eq!(blockchain.get_block(&hash)?.hash, hash);
+
+    fs::remove_dir_all("./decoding_keeps_stored_hash")?;
+    Ok(())
+}
+
+#[test]
+fn canonical_encoding() -> Result<(), Box<dyn Error>> {
+    let blockchain = make_blockchain("./canonical_encoding_test")?;
+
+    let block = mine_block(&blockchain)?;
+    let block_bytes = block.to_bytes()?;
+    assert_eq!(Block::from_bytes(&block_bytes)?.to_bytes()?, block_bytes);
+
+    let trailing_bytes = [block_bytes.clone(), vec![0u8]].concat();
+    let error = Block::from_bytes(&trailing_bytes).unwrap_err();
+    assert_eq!(error.kind(), &BlockErrorKind::TrailingBytes { found: 1 });
+
+    let truncated_bytes = &block_bytes[..block_bytes.len() - 1];
+    let error = Block::from_bytes(truncated_bytes).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockErrorKind::Truncated {
+            needed: 32,
+            found: 31
+        }
+    );
+
+    let mut header = block.header.clone();
+    header.entry_difficulty = f32::NAN;
+    let error = BlockHeader::from_bytes(&header.to_bytes()?).unwrap_err();
+    assert_eq!(error.kind(), &EncodingErrorKind::NaN);
+
+    // Negative zero equals zero, but would give the header another hash
+    header.entry_difficulty = -0f32;
+    let error = BlockHeader::from_bytes(&header.to_bytes()?).unwrap_err();
+    assert_eq!(error.kind(), &EncodingErrorKind::NegativeZero);
+
+    let info_bytes = blockchain.info.to_bytes();
+    assert!(BlockchainInfo::from_bytes(&info_bytes)? == blockchain.info);
+
+    // is_empty is the first field, and a bool can only be 0 or 1
+    let mut info_bytes = info_bytes;
+    info_bytes[0] = 2;
+    let error = BlockchainInfo::from_bytes(&info_bytes).unwrap_err();
+    assert_eq!(error.kind(), &EncodingErrorKind::InvalidBool { found: 2 });
+
+    fs::remove_dir_all("./canonical_encoding_test")?;
+    Ok(())
+}
+
+#[test]
+fn deployment_activation() -> Result<(), Box<dyn Error>> {
+    // Windows of three blocks, starting signalling in the second one
+    let params = NetworkParams {
+        signalling_window: 3,
+        activation_threshold: 2,
+        deployments: vec![Deployment::new(DeploymentId::TestDummy, 28, 3, 100)],
+        ..NetworkParams::default()
+    };
+    let mut blockchain =
+        make_blockchain_with_params("./deployment_activation_test", params)?;
+
+    let expected_states = [
+        (1, ThresholdState::Defined),
+        (3, ThresholdState::Started),
+        (6, ThresholdState::LockedIn),
+        (9, ThresholdState::Active),
+    ];
+    for (height, expected_state) in expected_states {
+        while blockchain.info.height + 1 < height {
+            blockchain.add_block(mine_block(&blockchain)?)?;
+        }
+
+        let state =
+            blockchain.deployment_state(DeploymentId::TestDummy, height)?;
+        assert_eq!(state, expected_state, "at height {}", height);
+    }
+
+    // Blocks only signal while the deployment is started or locked in
+    let version = blockchain.block_version(9)?;
+    assert_eq!(version, VERSIONBITS_TOP_BITS);
+    blockchain.del_top_block()?;
+    let version = blockchain.block_version(8)?;
+    assert_eq!(version, VERSIONBITS_TOP_BITS | 1 << 28);
+
+    let mut block = mine_block(&blockchain)?;
+    block.header.version = 1;
+    let error = blockchain.add_block(block).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockchainErrorKind::BlockVersionInvalid { found: 1 }
+    );
+
+    fs::remove_dir_all("./deployment_activation_test")?;
+    Ok(())
+}
+
+#[test]
+fn deployment_state_transitions() {
+    let deployment = Deployment::new(DeploymentId::TestDummy, 1, 10, 20);
+
+    assert!(deployment.is_signalled_by(VERSIONBITS_TOP_BITS | 1 << 1));
+    assert!(!deployment.is_signalled_by(VERSIONBITS_TOP_BITS | 1 << 2));
+    assert!(!deployment.is_signalled_by(1 << 1));
+
+    use ThresholdState::*;
+    let transitions = [
+        (Defined, 5, 0, Defined),
+        (Defined, 10, 0, Started),
+        (Defined, 20, 0, Failed),
+        (Started, 15, 1, Started),
+        (Started, 15, 2, LockedIn),
+        (Started, 20, 1, Failed),
+        // Locking in wins over timing out
+        (Started, 20, 2, LockedIn),
+        (LockedIn, 25, 0, Active),
+        (Active, 30, 0, Active),
+        (Failed, 30, 2, Failed),
+    ];
+    for (state, window_start, signals, expected_state) in transitions {
+        let next_state =
+            deployment.next_state(state, window_start, signals, 2);
+        assert_eq!(next_state, expected_state, "{:?}", (state, window_start));
+    }
+}
+
+#[test]
+fn malformed_entries_are_rejected() -> Result<(), Box<dyn Error>> {
+    let entry = make_entry()?.entry().clone();
+    let bytes = entry.to_bytes()?;
+    assert_eq!(Entry::from_bytes(&bytes)?, entry);
+
+    // Every truncation is an error rather than a panic
+    for length in 0..bytes.len() {
+        let error = Entry::from_bytes(&bytes[..length]).unwrap_err();
+        assert!(
+            matches!(error.kind(), BlockErrorKind::Truncated { .. }),
+            "{}",
+            error
+        );
+    }
+
+    let trailing_bytes = [bytes.clone(), vec![0u8; 2]].concat();
+    let error = Entry::from_bytes(&trailing_bytes).unwrap_err();
+    assert_eq!(error.kind(), &BlockErrorKind::TrailingBytes { found: 2 });
+
+    let mut unknown_version = bytes.clone();
+    unknown_version[0] = 0xff;
+    let error = Entry::from_bytes(&unknown_version).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockErrorKind::UnknownEntryVersion { found: 0xff }
+    );
+
+    // The key type comes after the version, the coinfile hashes, the output
+    // hash, the payload and the flags of the unset time locks
+    let key_type_offset =
+        2 + 32 * entry.coinfile_hashes.len() + 32 + 2 + entry.payload.len() + 2;
+    let mut unknown_key_type = bytes.clone();
+    unknown_key_type[key_type_offset] = 0xff;
+    let error = Entry::from_bytes(&unknown_key_type).unwrap_err();
+    assert_eq!(error.kind(), &BlockErrorKind::UnknownKeyType { found: 0xff });
+
+    // An entry length that no entry can have is rejected before it's read
+    let fields = HeaderFields {
+        version: VERSIONBITS_TOP_BITS,
+        previous_hash: [0u8; 32],
+        height: 1,
+        timestamp: 0,
+        target: 0x1d00_ffff,
+        entry_difficulty_multiplier: 0f32,
+        max_allowed_entry_difficulty: 0f32,
+        min_entry_difficulty: 0,
+        miner_address: [0u8; 32],
+    };
+    let header = BlockHeader::new(fields, [0u8; 28], 0f32, vec![0u8; 96]);
+    let block = Block {
+        entries: vec![entry],
+        header,
+        randomx_input: Vec::new(),
+        hash: [0u8; 32],
+    };
+    let mut block_bytes = block.to_bytes()?;
+    let header_len = block.header.to_bytes()?.len();
+    let entry_len_position = header_len + 4;
+    block_bytes[entry_len_position..entry_len_position + 4]
+        .copy_from_slice(&u32::MAX.to_le_bytes());
+    let error = Block::from_bytes(&block_bytes).unwrap_err();
+    assert_eq!(
+        error.kind(),
+        &BlockErrorKind::LengthTooBig {
+            maximum: MAX_ENTRY_SIZE,
+            found: u32::MAX as usize,
+        }
+    );
+    assert_eq!(error.entry_index(), Some(0));
+
+    Ok(())
+}
+
+#[test]
+fn target_comparison() {
+    // Compact targets keep the three most significant bytes
+    let target = U256::from_compact(0x1d00_ffff);
+    let mut expected = [0u8; 32];
+    expected[4..6].copy_from_slice(&[0xff, 0xff]);
+    assert_eq!(target.to_be_bytes(), expected);
+    assert_eq!(target.to_compact(), 0x1d00_ffff);
+
+    // The sign bit is never set, the mantissa moves down a byte instead
+    let target = U256::from_u64(0x80);
+    assert_eq!(target.to_compact(), 0x0200_8000);
+    assert_eq!(U256::from_compact(0x0200_8000), target);
+
+    assert_eq!(U256::from_compact(0xff7f_ffff), U256::MAX);
+    assert_eq!(U256::from_compact(0x0100_00ff), U256::ZERO);
+
+    // Scaling a target is exact, even past 64 bits
+    let target = U256::from_compact(0x2000_ffff);
+    assert_eq!(target.mul_div(3, 3), target);
+    assert_eq!(target.mul_div(2, 1).mul_div(1, 2), target);
+    assert_eq!(U256::MAX.mul_div(2, 1), U256::MAX);
+
+    // Division is exact too
+    assert_eq!(target.mul_div(3, 1) / U256::from_u64(3), target);
+    let difficulty = U256::MAX / U256::from_compact(0x1d00_ffff);
+    assert_eq!(difficulty, U256::from_u64(0x1_0001_0001));
+    assert_eq!(U256::MAX / U256::MAX, U256::ONE);
+
+    // Lowering the difficulty of a target uses integers only: at a
+    // difficulty of 16, lowering it by 8 doubles the target
+    let target = U256::MAX >> 4;
+    assert_eq!(U256::MAX / target, U256::from_u64(16));
+    assert_eq!(target::lower_difficulty(target, 0), target);
+    assert_eq!(target::lower_difficulty(target, 8), target.mul_div(2, 1));
+    assert_eq!(target::lower_difficulty(target, 15), U256::MAX);
+
+    // Difficulty moves in steps far smaller than a factor of two
+    let easier = target::target_from_difficulty(100f64);
+    let harder = target::target_from_difficulty(101f64);
+    assert!(harder < easier);
+    let difficulty = target::difficulty_from_target(harder);
+    assert!((difficulty - 101f64).abs() < 0.001);
+
+    // Hashes compare as big endian integers
+    let mut hash = harder.to_be_bytes();
+    assert!(U256::from_be_bytes(hash) <= harder);
+    hash[31] = hash[31].wrapping_add(1);
+    assert!(U256::from_be_bytes(hash) > harder);
+    assert!(target::hash_work(&[0u8; 32]) > target::hash_work(&[1u8; 32]));
+}
+
+#[test]
+fn difficulty_algorithms_hold_steady() {
+    let target = target::target_from_difficulty(1_000_000f64);
+    let blocks: Vec<RetargetBlock> = (0..100)
+        .map(|index| RetargetBlock {
+            timestamp: 1_000_000 + index * BLOCK_TIME,
+            target,
+        })
+        .collect();
+
+    // Blocks coming exactly on time keep the target where it is
+    let algorithms: [&dyn DifficultyAlgorithm; 2] =
+        [&SimpleAverage::default(), &Lwma::default()];
+    for algorithm in algorithms {
+        let next_target = algorithm.next_target(&blocks, BLOCK_TIME);
+        let difficulty = target::difficulty_from_target(next_target);
+        let error = (difficulty - 1_000_000f64).abs();
+        assert!(error < 1f64, "{}", algorithm.name());
+    }
+
+    // A timestamp far in the future barely moves the LWMA target
+    let mut blocks = blocks;
+    blocks[98].timestamp += 1_000_000;
+    let next_target = Lwma::default().next_target(&blocks, BLOCK_TIME);
+    assert!(next_target < target.mul_div(12, 10));
+}
+
+#[test]
+fn difficulty_algorithms_follow_hash_rate() {
+    const HEIGHTS: usize = 2000;
+    const STEP_HEIGHT: usize = 1000;
+
+    // The hash rate jumps tenfold halfway through
+    let hash_rate = |height: usize| {
+        if height < STEP_HEIGHT {
+            1000f64
+        } else {
+            10_000f64
+        }
+    };
+
+    let simple_average =
+        simulate(&SimpleAverage::default(), hash_rate, HEIGHTS);
+    let lwma = simulate(&Lwma::default(), hash_rate, HEIGHTS);
+
+    // Both settle on the block time while the hash rate is steady
+    for solve_times in [&simple_average, &lwma] {
+        let steady = mean(&solve_times[500..STEP_HEIGHT]);
+        assert!((steady - BLOCK_TIME as f64).abs() < 15f64, "{}", steady);
+    }
+
+    // The LWMA is back near the block time much sooner after the jump
+    let after = STEP_HEIGHT + 100..STEP_HEIGHT + 300;
+    let simple_average_after = mean(&simple_average[after.clone()]);
+    let lwma_after = mean(&lwma[after]);
+    let lwma_error = (lwma_after - BLOCK_TIME as f64).abs();
+    assert!(lwma_error < 15f64, "{}", lwma_after);
+    assert!(simple_average_after < lwma_after / 2f64);
+}
+
+#[test]
+fn seed_epochs() {
+    let lag = randomx::SEED_EPOCH_LAG;
+    let length = randomx::SEED_EPOCH_LENGTH;
+
+    assert_eq!(randomx::seed_height(1), 0);
+    assert_eq!(randomx::seed_height(length + lag), 0);
+    assert_eq!(randomx::seed_height(length + lag + 1), length);
+    assert_eq!(randomx::seed_height(2 * length + lag), length);
+    assert_eq!(randomx::seed_height(2 * length + lag + 1), 2 * length);
+}
+
+/// Hashing through the shared caches gives the same hashes as a fresh cache,
+/// including after a key has been evicted.
+#[test]
+fn cached_randomx_hashes_match() -> Result<(), Box<dyn Error>> {
+    let flags = RandomxFlags::default();
+
+    for round in 0..2 {
+        for key_index in 0..=randomx::MAX_CACHED_KEYS {
+            let key = [key_index as u8; 32];
+            let input = [round as u8; 8];
+
+            let cache = RandomxCache::new(flags, &key)?;
+            let vm = RandomxVm::new(flags, &cache)?;
+
+            assert_eq!(randomx::hash(&key, &input)?, vm.hash(&input));
+        }
+    }
+
+    Ok(())
+}
+
+/// Params whose threshold keys deployment can activate after a few blocks, in
+/// windows of three.
+fn threshold_keys_params() -> NetworkParams {
+    NetworkParams {
+        signalling_window: 3,
+        activation_threshold: 2,
+        deployments: vec![Deployment::new(
+            DeploymentId::ThresholdKeys,
+            0,
+            0,
+            u64::MAX,
+        )],
+        ..NetworkParams::default()
+    }
+}
+
+/// Mines blocks, which signal for the threshold keys deployment, until it is
+/// active at the next height.
+fn activate_threshold_keys(
+    blockchain: &mut Blockchain,
+) -> Result<(), Box<dyn Error>> {
+    while !blockchain.allows_threshold_keys(blockchain.info.height + 1)? {
+        blockchain.add_block(mine_block(blockchain)?)?;
+    }
+
+    Ok(())
+}
+
 fn make_blockchain(dir: &str) -> Result<Blockchain, Box<dyn Error>> {
+    make_blockchain_with_params(dir, NetworkParams::default())
+}
+
+fn make_blockchain_with_params(
+    dir: &str,
+    params: NetworkParams,
+) -> Result<Blockchain, Box<dyn Error>> {
     let _ = fs::remove_dir_all(dir);
-    let mut blockchain = Blockchain::new(dir)?;
+    let mut blockchain = Blockchain::with_params(dir, params)?;
 
     blockchain.info.network_adjusted_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_secs();
-    blockchain.info.difficulty = START_DIFFICULTY;
+    blockchain.info.target =
+        target::target_from_difficulty(START_DIFFICULTY).to_compact();
 
     Ok(blockchain)
 }
 
 /// This is a very inefficient, and single threaded miner, this is used purely for testing
 fn mine_block(blockchain: &Blockchain) -> Result<Block, Box<dyn Error>> {
-    let mut block = Block::new(
-        blockchain.info.top_block_hash,
-        blockchain.info.height + 1,
-        vec![make_entry()?, make_entry()?],
-        vec![0u8],
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs(),
-        blockchain.info.difficulty,
-        blockchain.info.entry_difficulty_multiplier,
-        blockchain.info.max_allowed_entry_difficulty,
-        [0u8; 32],
-    )?;
+    mine_block_with_entries(blockchain, vec![make_entry()?, make_entry()?])
+}
 
-    let difficulty_target =
-            blockchain.info.difficulty
-            - block.entry_difficulty()?
-            * block.header.entry_difficulty_multiplier;
+fn mine_block_with_entries(
+    blockchain: &Blockchain,
+    entries: Vec<MempoolEntry>,
+) -> Result<Block, Box<dyn Error>> {
+    mine_block_for(blockchain, entries, [0u8; 32])
+}
+
+fn mine_block_for(
+    blockchain: &Blockchain,
+    entries: Vec<MempoolEntry>,
+    miner_address: [u8; 32],
+) -> Result<Block, Box<dyn Error>> {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    mine_block_at(blockchain, entries, miner_address, timestamp)
+}
+
+fn mine_block_at(
+    blockchain: &Blockchain,
+    entries: Vec<MempoolEntry>,
+    miner_address: [u8; 32],
+    timestamp: u64,
+) -> Result<Block, Box<dyn Error>> {
+    let info = &blockchain.info;
+    let fields = HeaderFields {
+        version: blockchain.block_version(info.height + 1)?,
+        previous_hash: info.top_block_hash,
+        height: info.height + 1,
+        timestamp,
+        target: info.target,
+        entry_difficulty_multiplier: info.entry_difficulty_multiplier,
+        max_allowed_entry_difficulty: info.max_allowed_entry_difficulty,
+        min_entry_difficulty: info.min_entry_difficulty,
+        miner_address,
+    };
+    let mut block = Block::new(fields, entries, vec![0u8])?;
 
     let (randomx_input, block_hash) =
-        find_randomx_input(&block.header, difficulty_target)?;
+        find_randomx_input(
+            &block.header,
+            &blockchain.seed_key(block.header.height)?,
+        )?;
 
     block.randomx_input = randomx_input;
     block.hash = block_hash;
@@ -117,15 +1772,12 @@ fn mine_block(blockchain: &Blockchain) -> Result<Block, Box<dyn Error>> {
 
 fn find_randomx_input(
     header: &BlockHeader,
-    difficulty: f32,
+    seed_key: &[u8; 32],
 ) -> Result<(Vec<u8>, [u8; 32]), Box<dyn Error>> {
-    let header = header.clone();
-    let key = header.concat();
-
     let flags = RandomxFlags::default() | RandomxFlags::FULLMEM;
 
     const THREADS: u8 = 8;
-    let dataset = RandomxDataset::new(flags, &key, THREADS)?;
+    let dataset = RandomxDataset::new(flags, seed_key, THREADS)?;
     let vm = RandomxVm::new_fast(flags, &dataset)?;
 
     let mut nonce = Nonce::new();
@@ -133,20 +1785,9 @@ fn find_randomx_input(
     let complete_hash: [u8; 32];
 
     loop {
-        let hash = vm.hash(&nonce.0);
-
-        let leading_zeros = {
-            let mut leading_zeros = 0;
-            for i in hash.iter() {
-                leading_zeros += i.to_le().leading_zeros();
-                if i.leading_zeros() < 8 {
-                    break;
-                }
-            }
-            leading_zeros as usize
-        };
+        let hash = vm.hash(&header.pow_input(&nonce.0)?);
 
-        if 2usize.pow(leading_zeros as u32) >= difficulty as usize {
+        if header.meets_target(&hash) {
             complete_hash = hash;
             break;
         }
@@ -156,6 +1797,51 @@ fn find_randomx_input(
     Ok((nonce.0, complete_hash))
 }
 
+/// Replays a hash rate curve against `algorithm`, returning each block's
+/// solve time. Solve times are drawn at random around how long the block's
+/// difficulty takes at the current hash rate, with a fixed seed.
+fn simulate(
+    algorithm: &dyn DifficultyAlgorithm,
+    hash_rate: impl Fn(usize) -> f64,
+    heights: usize,
+) -> Vec<f64> {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let start_target =
+        target::target_from_difficulty(hash_rate(0) * BLOCK_TIME as f64);
+    let mut blocks = vec![
+        RetargetBlock {
+            timestamp: 0,
+            target: start_target,
+        },
+        RetargetBlock {
+            timestamp: BLOCK_TIME,
+            target: start_target,
+        },
+    ];
+
+    let mut solve_times = Vec::new();
+    for height in 0..heights {
+        let window = blocks.len().saturating_sub(algorithm.window());
+        let target = algorithm.next_target(&blocks[window..], BLOCK_TIME);
+
+        // Exponentially distributed, like the time to find a hash
+        let expected_time =
+            target::difficulty_from_target(target) / hash_rate(height);
+        let solve_time = -expected_time * (1f64 - rng.gen::<f64>()).ln();
+        solve_times.push(solve_time);
+
+        let timestamp = blocks.last().unwrap().timestamp + solve_time as u64;
+        blocks.push(RetargetBlock { timestamp, target });
+    }
+
+    solve_times
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
 struct Nonce(Vec<u8>);
 
 impl Nonce {
@@ -165,34 +1851,68 @@ impl Nonce {
 
     fn increment(&mut self) {
         for byte in self.0.iter_mut() {
-            if byte < &mut 255 {
-                *byte += 1;
-                break;
+            let (incremented, overflowed) = byte.overflowing_add(1);
+            *byte = incremented;
+
+            if !overflowed {
+                return;
             }
         }
 
-        if self.0.last().unwrap() == &255 {
-            self.0.push(0);
-        }
+        self.0.push(1);
     }
 }
 
 fn make_entry() -> Result<MempoolEntry, Box<dyn Error>> {
-    let mut rng = OsRng::default();
-    let private_key = PrivateKey::generate(&mut rng);
-    let public_key = private_key.public_key();
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+
+    make_entry_with(&private_key, |_| {})
+}
+
+fn make_entry_spending(
+    coinfile_hash: [u8; 32],
+) -> Result<MempoolEntry, Box<dyn Error>> {
+    let private_key = PrivateKey::generate(&mut OsRng::default());
+
+    make_entry_with(&private_key, |entry| {
+        entry.coinfile_hashes = vec![coinfile_hash];
+    })
+}
 
-    let mut coinfile_hash = [0u8; 8];
-    rng.fill_bytes(&mut coinfile_hash);
+/// An entry owned by `private_key`, made by `make_unsigned_entry` and signed.
+fn make_entry_with(
+    private_key: &PrivateKey,
+    customise: impl FnOnce(&mut Entry),
+) -> Result<MempoolEntry, Box<dyn Error>> {
+    let entry = make_unsigned_entry(private_key, customise);
+
+    Ok(MempoolEntry::sign(entry, MAIN_CHAIN_ID, private_key)?)
+}
+
+/// An entry owned by `private_key` that spends a random coinfile, changed by
+/// `customise` before its proof of work is found. It still needs signing.
+fn make_unsigned_entry(
+    private_key: &PrivateKey,
+    customise: impl FnOnce(&mut Entry),
+) -> Entry {
+    let mut coinfile_hash = [0u8; 32];
+    OsRng::default().fill_bytes(&mut coinfile_hash);
 
     let mut entry = Entry::new(
         vec![coinfile_hash],
-		[0; 8],
-        Some(public_key.as_bytes()),
+		[0; 32],
+        Vec::new(),
+        Some(private_key.public_key().as_bytes()),
         None,
         vec![0],
     );
+    customise(&mut entry);
 
+    with_work(entry)
+}
+
+/// Finds a proof of work for the entry as it is.
+fn with_work(mut entry: Entry) -> Entry {
     let mut nonce = Nonce::new();
 
     loop {
@@ -203,9 +1923,5 @@ fn make_entry() -> Result<MempoolEntry, Box<dyn Error>> {
         nonce.increment();
     }
 
-    let signature = private_key.sign(entry.to_bytes()?).as_bytes();
-
-    let mempool_entry = MempoolEntry::new(entry, signature);
-
-    Ok(mempool_entry)
+    entry
 }
@@ -18,6 +18,9 @@ use tokio::{
     time::{sleep, Duration},
 };
 
+extern crate tracing;
+use tracing::{debug, debug_span, error, info, info_span, warn, Instrument, Level};
+
 use std::sync::Arc;
 
 #[derive(Debug, Clone, Copy)]
@@ -32,6 +35,8 @@ pub struct Configuration {
 
     pub max_node_connections: usize,
     pub max_wallet_connections: usize,
+
+    pub log_level: Level,
 }
 
 impl Default for Configuration {
@@ -47,6 +52,8 @@ impl Default for Configuration {
 
             max_node_connections: 50,
             max_wallet_connections: 5000,
+
+            log_level: Level::INFO,
         }
     }
 }
@@ -114,21 +121,39 @@ impl Node {
 }
 
 async fn host(state: Arc<AtomicCell<State>>) {
-    let listener = TcpListener::bind(format!(
-        "127.0.0.1:{}",
-        state.load().configuration.port
-    ))
-    .await
-    .unwrap();
+    let port = state.load().configuration.port;
+
+    let listener = match TcpListener::bind(format!("127.0.0.1:{}", port)).await
+    {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!(port, %error, "failed to bind listener");
+            return;
+        }
+    };
+
+    info!(port, "listening for connections");
 
     loop {
         if &state.load().active == &true {
-            if let Ok((socket, _)) = listener.accept().await {
-                let handle_state = state.clone();
+            match listener.accept().await {
+                Ok((socket, address)) => {
+                    let handle_state = state.clone();
+                    let span = info_span!("connection", peer = %address);
+
+                    tokio::spawn(
+                        async move {
+                            debug!("connection opened");
+                            handle(socket, handle_state).await;
+                            debug!("connection closed");
+                        }
+                        .instrument(span),
+                    );
+                }
 
-                tokio::spawn(async move {
-                    handle(socket, handle_state).await;
-                });
+                Err(error) => {
+                    warn!(%error, "failed to accept connection");
+                }
             }
         } else {
             break;
@@ -137,163 +162,183 @@ async fn host(state: Arc<AtomicCell<State>>) {
 }
 
 async fn handle(socket: TcpStream, state: Arc<AtomicCell<State>>) {
-    if let Ok(mut connection) = server::handshake(socket).await {
-        let mut current_state = state.load();
-
-        while let Some(result) = connection.accept().await {
-            if let Ok((request, mut respond)) = result {
-                let builder = Response::builder();
-
-                let mut client: Option<Client> = None;
-                let mut response: Option<Response<()>> = None;
-
-                if request.headers().contains_key("client") {
-                    match request
-                        .headers()
-                        .get("client")
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                    {
-                        "node" => {
-                            if current_state.node_connections
-                                != current_state
-                                    .configuration
-                                    .max_node_connections
-                            {
-                                response = Some(
-                                    builder
-                                        .status(StatusCode::OK)
-                                        .body(())
-                                        .unwrap(),
-                                );
-
-                                client = Some(Client::Node);
-
-                                current_state.node_connections =
-                                    current_state.node_connections + 1;
-                            } else {
-                                response = Some(
-                                    builder
-                                        .status(
-                                            StatusCode::INTERNAL_SERVER_ERROR,
-                                        )
-                                        .body(())
-                                        .unwrap(),
-                                );
-                            }
-                        }
+    let mut connection = match server::handshake(socket).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            warn!(%error, "http/2 handshake failed");
+            return;
+        }
+    };
 
-                        "wallet" => {
-                            if current_state.wallet_connections
-                                != current_state
-                                    .configuration
-                                    .max_wallet_connections
-                            {
-                                response = Some(
-                                    builder
-                                        .status(StatusCode::OK)
-                                        .body(())
-                                        .unwrap(),
-                                );
-
-                                client = Some(Client::Wallet);
-
-                                current_state.wallet_connections =
-                                    current_state.wallet_connections + 1;
-                            } else {
-                                response = Some(
-                                    builder
-                                        .status(
-                                            StatusCode::INTERNAL_SERVER_ERROR,
-                                        )
-                                        .body(())
-                                        .unwrap(),
-                                );
-                            }
-                        }
+    let mut current_state = state.load();
 
-                        _ => {
-                            response = Some(
-                                builder
-                                    .status(StatusCode::BAD_REQUEST)
-                                    .body(())
-                                    .unwrap(),
-                            );
-                        }
-                    }
+    while let Some(result) = connection.accept().await {
+        let (request, mut respond) = match result {
+            Ok(request) => request,
+            Err(error) => {
+                warn!(%error, "failed to accept request");
+                continue;
+            }
+        };
+
+        let builder = Response::builder();
+
+        let mut client: Option<Client> = None;
+        let response: Response<()>;
+
+        match request.headers().get("client").map(|value| value.to_str()) {
+            Some(Ok("node")) => {
+                if current_state.node_connections
+                    != current_state.configuration.max_node_connections
+                {
+                    response =
+                        builder.status(StatusCode::OK).body(()).unwrap();
+
+                    client = Some(Client::Node);
+
+                    current_state.node_connections =
+                        current_state.node_connections + 1;
                 } else {
-                    response = Some(
-                        builder
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(())
-                            .unwrap(),
+                    warn!(
+                        connections = current_state.node_connections,
+                        "rejecting node, connection limit reached"
                     );
+
+                    response = builder
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(())
+                        .unwrap();
                 }
+            }
 
-                if client.is_none() == true {
-                    respond.send_response(response.unwrap(), true);
+            Some(Ok("wallet")) => {
+                if current_state.wallet_connections
+                    != current_state.configuration.max_wallet_connections
+                {
+                    response =
+                        builder.status(StatusCode::OK).body(()).unwrap();
+
+                    client = Some(Client::Wallet);
+
+                    current_state.wallet_connections =
+                        current_state.wallet_connections + 1;
                 } else {
-                    state.swap(current_state);
-
-                    if let Ok(mut stream) =
-                        respond.send_response(response.unwrap(), false)
-                    {
-                        match client.unwrap() {
-                            Client::Node => {
-                                stream.reserve_capacity(
-                                    current_state.configuration.max_node_size,
-                                );
-                            }
-
-                            Client::Wallet => {
-                                stream.reserve_capacity(
-                                    current_state.configuration.max_wallet_size,
-                                );
-                            }
-                        }
+                    warn!(
+                        connections = current_state.wallet_connections,
+                        "rejecting wallet, connection limit reached"
+                    );
 
-                        direct_stream(
-                            &mut stream,
-                            request,
-                            client.unwrap(),
-                            state.clone(),
-                        )
-                        .await;
-                    }
+                    response = builder
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(())
+                        .unwrap();
+                }
+            }
+
+            Some(Ok(unknown)) => {
+                debug!(client = unknown, "rejecting unknown client type");
+
+                response =
+                    builder.status(StatusCode::BAD_REQUEST).body(()).unwrap();
+            }
+
+            Some(Err(error)) => {
+                debug!(%error, "rejecting unreadable client header");
+
+                response =
+                    builder.status(StatusCode::BAD_REQUEST).body(()).unwrap();
+            }
+
+            None => {
+                debug!("rejecting request without client header");
+
+                response =
+                    builder.status(StatusCode::BAD_REQUEST).body(()).unwrap();
+            }
+        }
+
+        let client = match client {
+            Some(client) => client,
+            None => {
+                if let Err(error) = respond.send_response(response, true) {
+                    warn!(%error, "failed to send response");
+                }
+                continue;
+            }
+        };
+
+        state.swap(current_state);
 
-                    match client.unwrap() {
+        let span = debug_span!(
+            "request",
+            ?client,
+            path = request.uri().path()
+        );
+
+        async {
+            match respond.send_response(response, false) {
+                Ok(mut stream) => {
+                    match client {
                         Client::Node => {
-                            current_state.node_connections =
-                                current_state.node_connections - 1;
+                            stream.reserve_capacity(
+                                current_state.configuration.max_node_size,
+                            );
                         }
 
                         Client::Wallet => {
-                            current_state.wallet_connections =
-                                current_state.wallet_connections - 1;
+                            stream.reserve_capacity(
+                                current_state.configuration.max_wallet_size,
+                            );
                         }
                     }
 
-                    state.swap(current_state);
+                    direct_stream(&mut stream, request, client, state.clone())
+                        .await;
                 }
+
+                Err(error) => {
+                    warn!(%error, "failed to send response");
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+
+        match client {
+            Client::Node => {
+                current_state.node_connections =
+                    current_state.node_connections - 1;
+            }
+
+            Client::Wallet => {
+                current_state.wallet_connections =
+                    current_state.wallet_connections - 1;
             }
         }
+
+        state.swap(current_state);
     }
 }
 
 async fn direct_stream(
     stream: &mut SendStream<Bytes>,
     request: Request<RecvStream>,
-    client: Client,
-    state: Arc<AtomicCell<State>>,
+    _client: Client,
+    _state: Arc<AtomicCell<State>>,
 ) {
-    match request.uri().path() {
+    let result = match request.uri().path() {
         "/ping" => {
-            stream.send_data(Bytes::from("pong\n"), true).unwrap();
+            debug!("routing to ping");
+            stream.send_data(Bytes::from("pong\n"), true)
         }
 
-        _ => {
-            stream.send_data(Bytes::new(), true).unwrap();
+        path => {
+            debug!(path, "no route for path");
+            stream.send_data(Bytes::new(), true)
         }
+    };
+
+    if let Err(error) = result {
+        warn!(%error, "failed to send data");
     }
 }
@@ -22,6 +22,8 @@ async fn create_custom_node() -> Result<(), Box<dyn Error>> {
 
         max_node_connections: 1,
         max_wallet_connections: 100,
+
+        log_level: tracing::Level::DEBUG,
     });
 
     Ok(())
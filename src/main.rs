@@ -1,3 +1,15 @@
-fn main() {
-    println!("Hello, world!");
+extern crate padlock_networking;
+use padlock_networking::{Configuration, Node};
+
+extern crate tracing_subscriber;
+
+#[tokio::main]
+async fn main() {
+    let configuration = Configuration::default();
+
+    tracing_subscriber::fmt()
+        .with_max_level(configuration.log_level)
+        .init();
+
+    Node::create(configuration).listen().await;
 }